@@ -13,12 +13,17 @@ echo "LAMBDA=${LAMBDA}"
 cargo build
 BIN=target/debug/bitcoin
 mkdir -p expt
+# the development keys of the seeds 0 to 2, which nodes only pick up by themselves on regtest
+for i in 0 1 2; do printf "0${i}%.0s" {1..32} > expt/dev${i}.hex; done
+# mainnet starts without coins: a local chain spec with its rules gives them to the key of seed 0
+ico=$(./${BIN} address expt/dev0.hex | sed -n 's/^address: //p')
+echo '{"name": "sanity", "genesis_timestamp": 0, "difficulty": "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff", "allocations": [{"address": "'${ico}'", "amount": 1000000}]}' > expt/chain.json
 
-./${BIN} --p2p 127.0.0.1:6000 --api 127.0.0.1:7000 > expt/1.out &
+./${BIN} --p2p 127.0.0.1:6000 --api 127.0.0.1:7000 --chain expt/chain.json --key expt/dev0.hex > expt/1.out &
 p1=$!
-./${BIN} --p2p 127.0.0.1:6001 --api 127.0.0.1:7001 -c 127.0.0.1:6000 > expt/2.out &
+./${BIN} --p2p 127.0.0.1:6001 --api 127.0.0.1:7001 --chain expt/chain.json --key expt/dev1.hex -c 127.0.0.1:6000 > expt/2.out &
 p2=$!
-./${BIN} --p2p 127.0.0.1:6002 --api 127.0.0.1:7002 -c 127.0.0.1:6001 > expt/3.out &
+./${BIN} --p2p 127.0.0.1:6002 --api 127.0.0.1:7002 --chain expt/chain.json --key expt/dev2.hex -c 127.0.0.1:6001 > expt/3.out &
 p3=$!
 
 echo "p1=$p1, p2=$p2, p3=$p3"
@@ -30,7 +35,7 @@ for i in {0..2}; do
 	#echo "curl $i"
 	retcode=1
 	while [ $retcode -gt 0 ]; do
-		curl -s http://127.0.0.1:700${i}/miner/start\?lambda\=${LAMBDA} >/dev/null
+		curl -s -X POST http://127.0.0.1:700${i}/miner/start\?lambda\=${LAMBDA} >/dev/null
 		retcode=$?
 		#echo "retcode=$retcode"
 	done
@@ -0,0 +1,191 @@
+use serde::Serialize;
+
+use crate::blockchain::Blockchain;
+use crate::blockchain::spec::as_string;
+use crate::error::StorageError;
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{SignedTransaction, TransactionMempool};
+
+/// Transfers in a page of an address history, when the request does not say
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+/// Largest page of an address history
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// A block with its header fields and transactions
+#[derive(Serialize, Debug)]
+pub struct BlockView {
+    #[serde(with = "as_string")]
+    pub hash: H256,
+    pub height: u32,
+    pub confirmations: u32, // 0 outside the longest chain
+    #[serde(with = "as_string")]
+    pub parent_hash: H256,
+    #[serde(with = "as_string")]
+    pub difficulty: H256,
+    pub timestamp: u64, // ms since the epoch, u128 is not a JSON-RPC value
+    pub nonce: u32,
+    pub extra_nonce: u32,
+    #[serde(with = "as_string")]
+    pub merkle_root: H256,
+    pub size: usize, // serialized size in bytes
+    pub transactions: Vec<TransactionView>,
+}
+
+/// A transaction, with the block confirming it
+#[derive(Serialize, Debug)]
+pub struct TransactionView {
+    #[serde(with = "as_string")]
+    pub hash: H256,
+    pub sender: String,
+    pub receiver: String,
+    pub value: Amount,
+    pub fee: Amount,
+    pub nonce: u32,
+    pub block: Option<TransactionLocation>, // None while the transaction waits in the mempool
+    pub confirmations: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TransactionLocation {
+    #[serde(with = "as_string")]
+    pub block_hash: H256,
+    pub height: u32,
+    pub position: usize, // index of the transaction in the block
+}
+
+/// A page of the transfers of an address in the longest chain, the most recent first
+#[derive(Serialize, Debug)]
+pub struct AddressHistory {
+    pub address: String,
+    pub balance: Amount, // at the tip
+    pub nonce: u32,
+    pub total: usize, // transfers of the address, in all pages
+    pub offset: usize,
+    pub transfers: Vec<Transfer>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+    #[serde(rename = "self")]
+    ToSelf, // the address sent coins to itself, it only pays the fee
+}
+
+#[derive(Serialize, Debug)]
+pub struct Transfer {
+    pub direction: Direction,
+    #[serde(with = "as_string")]
+    pub transaction: H256,
+    pub counterparty: String, // the receiver of outgoing transfers, the sender of incoming ones
+    pub value: Amount,
+    pub fee: Amount, // paid by the sender
+    #[serde(with = "as_string")]
+    pub block_hash: H256,
+    pub height: u32,
+    pub timestamp: u64,
+    pub confirmations: u32,
+}
+
+impl BlockView {
+    /// The block `hash`, which may be outside the longest chain
+    pub fn new(blockchain: &Blockchain, hash: H256) -> Result<Self, StorageError> {
+        let block = blockchain.get_block(hash)?;
+        let height = blockchain.get_height(hash)?;
+        let confirmations = blockchain.confirmations(hash);
+        let transactions = block.content.data.iter().enumerate().map(|(position, trx)| {
+            let location = TransactionLocation{block_hash: hash, height, position};
+            TransactionView::new(trx, Some(location), confirmations, blockchain.address_version())
+        }).collect();
+        Ok(BlockView{
+            hash,
+            height,
+            confirmations,
+            parent_hash: block.header.parent_hash,
+            difficulty: block.header.difficulty,
+            timestamp: block.header.timestamp as u64,
+            nonce: block.header.nonce,
+            extra_nonce: block.header.extra_nonce,
+            merkle_root: block.header.merkle_root,
+            size: block.size(),
+            transactions,
+        })
+    }
+}
+
+impl TransactionView {
+    fn new(trx: &SignedTransaction, block: Option<TransactionLocation>, confirmations: u32, address_version: u8) -> Self {
+        TransactionView{
+            hash: trx.hash(),
+            sender: trx.sender_address().encode(address_version).to_string(),
+            receiver: trx.receiver_address().encode(address_version).to_string(),
+            value: trx.value(),
+            fee: trx.fee(),
+            nonce: trx.account_nonce(),
+            block,
+            confirmations,
+        }
+    }
+
+    /// Look the transaction `hash` up in the longest chain, then in the mempool
+    pub fn find(blockchain: &Blockchain, mempool: &TransactionMempool, hash: H256) -> Result<Self, StorageError> {
+        let address_version = blockchain.address_version();
+        match blockchain.find_transaction(hash) {
+            Ok((block_hash, position)) => {
+                let block = blockchain.get_block(block_hash)?;
+                let location = TransactionLocation{block_hash, height: blockchain.get_height(block_hash)?, position};
+                let confirmations = blockchain.confirmations(block_hash);
+                Ok(Self::new(&block.content.data[position], Some(location), confirmations, address_version))
+            }
+            Err(_) => {
+                let trx = mempool.get_transaction(hash)?;
+                Ok(Self::new(&trx, None, 0, address_version))
+            }
+        }
+    }
+}
+
+impl AddressHistory {
+    /// Up to `limit` transfers of `address`, skipping the `offset` most recent ones
+    pub fn new(blockchain: &Blockchain, address: Address, offset: usize, limit: usize) -> Result<Self, StorageError> {
+        let address_version = blockchain.address_version();
+        let located = blockchain.address_transactions(address);
+        let mut transfers: Vec<Transfer> = vec![];
+        for (block_hash, position) in located.iter().rev().skip(offset).take(limit) {
+            let block = blockchain.get_block(*block_hash)?;
+            let trx = &block.content.data[*position];
+            let (sender, receiver) = (trx.sender_address(), trx.receiver_address());
+            let (direction, counterparty) = if sender == receiver {
+                (Direction::ToSelf, receiver)
+            } else if sender == address {
+                (Direction::Out, receiver)
+            } else {
+                (Direction::In, sender)
+            };
+            transfers.push(Transfer{
+                direction,
+                transaction: trx.hash(),
+                counterparty: counterparty.encode(address_version).to_string(),
+                value: trx.value(),
+                fee: trx.fee(),
+                block_hash: *block_hash,
+                height: blockchain.get_height(*block_hash)?,
+                timestamp: block.header.timestamp as u64,
+                confirmations: blockchain.confirmations(*block_hash),
+            });
+        }
+        // addresses that never received coins are not in the state
+        let (nonce, balance) = blockchain.get_state(blockchain.tip())?.get_balance(address).unwrap_or((0, Amount::ZERO));
+        Ok(AddressHistory{
+            address: address.encode(address_version).to_string(),
+            balance,
+            nonce,
+            total: located.len(),
+            offset,
+            transfers,
+        })
+    }
+}
@@ -1,15 +1,28 @@
+pub mod explorer;
+pub mod rpc;
+
 use serde::Serialize;
 use crate::blockchain::Blockchain;
 use crate::miner::Handle as MinerHandle;
 use crate::generator::Handle as GeneratorHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::miner::template::BlockTemplate;
+use crate::generator::workload::{Profile, Workload};
+use explorer::{AddressHistory, BlockView, TransactionView, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use crate::types::address::EncodedAddress;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::TransactionMempool;
 
+use crossbeam::channel::Sender;
 use log::info;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::str::FromStr;
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Request;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
@@ -20,6 +33,10 @@ pub struct Server {
 	generator: GeneratorHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<TransactionMempool>>,
+    templates: Arc<Mutex<HashMap<H256, BlockTemplate>>>, // templates handed out, by merkle root
+    shutdown: Sender<()>, // asks the node to shut down
+    rpc_access: Arc<rpc::Access>,
 }
 
 #[derive(Serialize)]
@@ -40,6 +57,19 @@ macro_rules! respond_result {
         $req.respond(resp).unwrap();
     }};
 }
+macro_rules! respond_error {
+    ( $req:expr, $status:expr, $message:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let payload = ApiResponse {
+            success: false,
+            message: $message.to_string(),
+        };
+        let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_header(content_type)
+            .with_status_code($status);
+        $req.respond(resp).unwrap();
+    }};
+}
 macro_rules! respond_json {
     ( $req:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -49,13 +79,59 @@ macro_rules! respond_json {
     }};
 }
 
+/// Whether a browser sends the request for a page: browsers add an `Origin` header to the requests a page
+/// sends to other sites. Such requests are refused where they change the node, even from this host.
+fn from_browser(req: &Request) -> bool {
+    req.headers().iter().any(|h| h.field.equiv("Origin"))
+}
+
+/// Whether the request comes from a program on this host
+fn is_local(req: &Request) -> bool {
+    req.remote_addr().ip().is_loopback() && !from_browser(req)
+}
+
+/// Requests that control the node must be POSTed by a program on this host. Not a GET, so that following a
+/// link or prefetching a page does not act on the node.
+fn check_control(req: &Request) -> Result<(), (u16, &'static str)> {
+    if *req.method() != Method::Post {
+        return Err((405, "this endpoint needs a POST request"));
+    }
+    if !is_local(req) {
+        return Err((403, "only local clients can control the node"));
+    }
+    Ok(())
+}
+
+/// Parse the query parameter `name`
+fn parse_param<T: FromStr>(params: &HashMap<String, String>, name: &str) -> Result<T, String>
+where T::Err: std::fmt::Display {
+    match params.get(name) {
+        Some(v) => v.parse::<T>().map_err(|e| format!("error parsing {}: {}", name, e)),
+        None => Err(format!("missing {}", name)),
+    }
+}
+
+/// Parse the query parameter `name` if it is present
+fn parse_optional_param<T: FromStr>(params: &HashMap<String, String>, name: &str) -> Result<Option<T>, String>
+where T::Err: std::fmt::Display {
+    if params.contains_key(name) {
+        parse_param(params, name).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         generator: &GeneratorHandle,
         network: &NetworkServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<TransactionMempool>>,
+        shutdown: &Sender<()>,
+        rpc_access: rpc::Access,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
@@ -64,6 +140,10 @@ impl Server {
             generator: generator.clone(),
             network: network.clone(),
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            templates: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: shutdown.clone(),
+            rpc_access: Arc::new(rpc_access),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
@@ -71,6 +151,10 @@ impl Server {
                 let generator = server.generator.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
+                let mempool = Arc::clone(&server.mempool);
+                let templates = Arc::clone(&server.templates);
+                let shutdown = server.shutdown.clone();
+                let rpc_access = Arc::clone(&server.rpc_access);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -83,6 +167,10 @@ impl Server {
                     };
                     match url.path() {
                         "/miner/start" => {
+                            if let Err((status, message)) = check_control(&req) {
+                                respond_error!(req, status, message);
+                                return;
+                            }
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
                             let lambda = match params.get("lambda") {
@@ -106,7 +194,96 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/stop" => {
+                            if let Err((status, message)) = check_control(&req) {
+                                respond_error!(req, status, message);
+                                return;
+                            }
+                            miner.stop();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
+                        "/miner/mine" => {
+                            if let Err((status, message)) = check_control(&req) {
+                                respond_error!(req, status, message);
+                                return;
+                            }
+                            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                            let blocks = match parse_param::<u64>(&params, "blocks") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            miner.mine(blocks);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/getblocktemplate" => {
+                            if let Err((status, message)) = check_control(&req) {
+                                respond_error!(req, status, message);
+                                return;
+                            }
+                            let blockchain = blockchain.lock().unwrap();
+                            let mut mempool = mempool.lock().unwrap();
+                            let template = match BlockTemplate::new(&blockchain, &mut mempool, miner.policy()) {
+                                Ok(t) => t,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let mut templates = templates.lock().unwrap();
+                            // templates on an older tip can not be submitted anymore
+                            templates.retain(|_, t| t.parent_hash == template.parent_hash);
+                            templates.insert(template.merkle_root, template.clone());
+                            respond_json!(req, template);
+                        }
+                        "/miner/submitblock" => {
+                            if let Err((status, message)) = check_control(&req) {
+                                respond_error!(req, status, message);
+                                return;
+                            }
+                            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                            let fields = parse_param::<H256>(&params, "merkle_root").and_then(|root| Ok((
+                                root,
+                                parse_param::<u128>(&params, "timestamp")?,
+                                parse_param::<u32>(&params, "nonce")?,
+                                parse_param::<u32>(&params, "extra_nonce")?,
+                            )));
+                            let (merkle_root, timestamp, nonce, extra_nonce) = match fields {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let template = match templates.lock().unwrap().get(&merkle_root) {
+                                Some(t) => t.clone(),
+                                None => {
+                                    respond_result!(req, false, format!("unknown or stale template {}", merkle_root));
+                                    return;
+                                }
+                            };
+                            let block = template.to_block(timestamp, nonce, extra_nonce);
+                            let mut blockchain = blockchain.lock().unwrap();
+                            if let Err(e) = blockchain.insert(&block) {
+                                respond_result!(req, false, format!("block rejected: {}", e));
+                                return;
+                            }
+                            drop(blockchain);
+                            mempool.lock().unwrap().remove_from_queue(&block.get_transaction_hashes());
+                            network.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+                            miner.update();
+                            respond_result!(req, true, block.hash());
+                        }
                         "/tx-generator/start" => {
+                            if let Err((status, message)) = check_control(&req) {
+                                respond_error!(req, status, message);
+                                return;
+                            }
                             // respond_result!(req, false, "unimplemented!");
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -128,6 +305,20 @@ impl Server {
                                     return;
                                 }
                             };
+                            // the workload changes only when a profile or a seed is given
+                            let profile = parse_optional_param::<Profile>(&params, "profile");
+                            let seed = parse_optional_param::<u64>(&params, "seed");
+                            match (profile, seed) {
+                                (Ok(None), Ok(None)) => {}
+                                (Ok(profile), Ok(seed)) => generator.set_workload(Workload {
+                                    profile: profile.unwrap_or_default(),
+                                    seed: seed.unwrap_or_default(),
+                                }),
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            }
                             generator.start(theta);
                             respond_result!(req, true, "ok");
                         }
@@ -135,6 +326,15 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/admin/shutdown" => {
+                            if let Err((status, message)) = check_control(&req) {
+                                respond_error!(req, status, message);
+                                return;
+                            }
+                            respond_result!(req, true, "shutting down");
+                            // a shutdown may already be requested
+                            let _ = shutdown.try_send(());
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -163,6 +363,104 @@ impl Server {
                             let v_string = v.to_string();
                             respond_json!(req, v_string);
 						}
+                        "/" => {
+                            // JSON-RPC 2.0, POSTed to the root as with bitcoind
+                            if *req.method() != Method::Post {
+                                respond_error!(req, 405, "JSON-RPC needs a POST request");
+                                return;
+                            }
+                            let authorization = req.headers().iter()
+                                .find(|h| h.field.equiv("Authorization"))
+                                .map(|h| h.value.as_str().to_string());
+                            match rpc_access.check(req.remote_addr().ip(), authorization.as_deref()) {
+                                Err(rpc::Denied::Forbidden) => {
+                                    respond_error!(req, 403, "this host can not call JSON-RPC methods");
+                                    return;
+                                }
+                                _ if from_browser(&req) => {
+                                    respond_error!(req, 403, "JSON-RPC methods can not be called from web pages");
+                                    return;
+                                }
+                                Err(rpc::Denied::Unauthorized) => {
+                                    let challenge = "WWW-Authenticate: Basic realm=\"jsonrpc\"".parse::<Header>().unwrap();
+                                    req.respond(Response::empty(401).with_header(challenge)).unwrap();
+                                    return;
+                                }
+                                Ok(()) => {}
+                            }
+                            let mut req = req;
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let node = rpc::Node{
+                                blockchain,
+                                mempool,
+                                network,
+                            };
+                            match node.handle(&body) {
+                                Some(response) => respond_json!(req, response),
+                                None => req.respond(Response::empty(204)).unwrap(),
+                            }
+                        }
+                        "/explorer/block" => {
+                            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                            let blockchain = blockchain.lock().unwrap();
+                            let hash = match (parse_optional_param::<H256>(&params, "hash"), parse_optional_param::<u32>(&params, "height")) {
+                                (Ok(Some(hash)), Ok(None)) => Ok(hash),
+                                (Ok(None), Ok(Some(height))) => blockchain.hash_at_height(height).map_err(|e| e.to_string()),
+                                (Err(e), _) | (_, Err(e)) => Err(e),
+                                _ => Err(String::from("expected either hash or height")),
+                            };
+                            match hash.and_then(|hash| BlockView::new(&blockchain, hash).map_err(|e| e.to_string())) {
+                                Ok(view) => respond_json!(req, view),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/explorer/tx" => {
+                            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                            let hash = match parse_param::<H256>(&params, "hash") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            match TransactionView::find(&blockchain, &mempool, hash) {
+                                Ok(view) => respond_json!(req, view),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/explorer/address" => {
+                            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+                            let fields = parse_param::<EncodedAddress>(&params, "address").and_then(|address| Ok((
+                                address,
+                                parse_optional_param::<usize>(&params, "offset")?.unwrap_or(0),
+                                parse_optional_param::<usize>(&params, "limit")?.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE),
+                            )));
+                            let (address, offset, limit) = match fields {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let address = match address.for_version(blockchain.address_version()) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            match AddressHistory::new(&blockchain, address, offset, limit) {
+                                Ok(history) => respond_json!(req, history),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
                         "/blockchain/state" =>{
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -185,7 +483,13 @@ impl Server {
                                 }
                             };
                             let blockchain = blockchain.lock().unwrap();
-                            let accounts: Vec<(String, String, String)> = blockchain.get_block_state(block_id);
+                            let accounts: Vec<(String, String, String)> = match blockchain.get_block_state(block_id) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
 
 							let v_string: Vec<String> = accounts.into_iter().map(|h| format!("{},{},{}", h.0, h.1, h.2)).collect();
                             respond_json!(req, v_string);
@@ -0,0 +1,281 @@
+use data_encoding::BASE64;
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::blockchain::Blockchain;
+use crate::network::message::Message;
+use crate::network::peer::Direction;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{SignedTransaction, TransactionMempool};
+use super::explorer::BlockView;
+
+// error codes of the JSON-RPC 2.0 specification
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// error codes of Bitcoin Core
+pub const INVALID_ADDRESS_OR_KEY: i64 = -5; // unknown block
+pub const DESERIALIZATION_ERROR: i64 = -22;
+pub const VERIFY_REJECTED: i64 = -26;
+pub const VERIFY_ALREADY_IN_CHAIN: i64 = -27;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new<M: Display>(code: i64, message: M) -> Self {
+        RpcError{code, message: message.to_string()}
+    }
+}
+
+/// Who may call the RPC methods, as `rpcallowip` and `rpcauth` do for Bitcoin Core
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    pub allow_ip: Vec<IpAddr>, // hosts other than this one allowed to call
+    pub password: Option<String>, // asked with HTTP basic authentication, under any user name
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    Forbidden, // the host is not allowed
+    Unauthorized, // the password is missing or wrong
+}
+
+impl Access {
+    /// Check a caller on the host `ip`, sending the `Authorization` header `authorization`
+    pub fn check(&self, ip: IpAddr, authorization: Option<&str>) -> Result<(), Denied> {
+        if !ip.is_loopback() && !self.allow_ip.contains(&ip) {
+            return Err(Denied::Forbidden);
+        }
+        let password = match &self.password {
+            Some(password) => password,
+            None => return Ok(()),
+        };
+        let credentials = authorization
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| BASE64.decode(encoded.trim().as_bytes()).ok())
+            .ok_or(Denied::Unauthorized)?;
+        // user:password
+        let given = match credentials.iter().position(|b| *b == b':') {
+            Some(i) => &credentials[i + 1..],
+            None => return Err(Denied::Unauthorized),
+        };
+        ring::constant_time::verify_slices_are_equal(given, password.as_bytes()).map_err(|_| Denied::Unauthorized)
+    }
+}
+
+/// The parts of the node the RPC methods act on
+pub struct Node {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<TransactionMempool>>,
+    pub network: NetworkServerHandle,
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "error": {"code": error.code, "message": error.message}, "id": id})
+}
+
+/// Parse the positional parameter `index`, a string
+fn string_param<T: FromStr>(params: &[Value], index: usize, name: &str) -> Result<T, RpcError>
+where T::Err: Display {
+    match params.get(index) {
+        Some(Value::String(s)) => s.parse::<T>().map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid {}: {}", name, e))),
+        Some(_) => Err(RpcError::new(INVALID_PARAMS, format!("{} must be a string", name))),
+        None => Err(RpcError::new(INVALID_PARAMS, format!("missing {}", name))),
+    }
+}
+
+/// Reject the calls with more than `max` parameters
+fn check_param_count(params: &[Value], max: usize) -> Result<(), RpcError> {
+    if params.len() > max {
+        return Err(RpcError::new(INVALID_PARAMS, format!("expected at most {} parameters, got {}", max, params.len())));
+    }
+    Ok(())
+}
+
+impl Node {
+    /// Answer the body of a JSON-RPC request, or of a batch of requests.
+    /// Returns None when there is nothing to answer, i.e. the body only holds notifications.
+    pub fn handle(&self, body: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(body) {
+            Ok(v) => v,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+        };
+        match request {
+            Value::Array(requests) if requests.is_empty() => {
+                Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "empty batch")))
+            }
+            Value::Array(requests) => {
+                let responses: Vec<Value> = requests.iter().filter_map(|r| self.handle_one(r)).collect();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses))
+                }
+            }
+            request => self.handle_one(&request),
+        }
+    }
+
+    fn handle_one(&self, request: &Value) -> Option<Value> {
+        let id = request.get("id").cloned();
+        let method = match (request.get("jsonrpc"), request.get("method")) {
+            (Some(version), Some(Value::String(method))) if version == "2.0" => method,
+            _ => {
+                let error = RpcError::new(INVALID_REQUEST, "expected a JSON-RPC 2.0 request");
+                return Some(error_response(id.unwrap_or(Value::Null), error));
+            }
+        };
+        let result = match request.get("params") {
+            None => self.call(method, &[]),
+            Some(Value::Array(params)) => self.call(method, params),
+            Some(_) => Err(RpcError::new(INVALID_PARAMS, "params must be an array")),
+        };
+        // requests without id are notifications, they get no response
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    fn call(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "getblockcount" => {
+                check_param_count(params, 0)?;
+                let blockchain = self.blockchain.lock().unwrap();
+                Ok(json!(blockchain.get_height(blockchain.tip()).unwrap()))
+            }
+            "getblock" => {
+                check_param_count(params, 2)?;
+                let hash: H256 = string_param(params, 0, "blockhash")?;
+                let verbosity = match params.get(1) {
+                    None => 1,
+                    Some(v) => v.as_u64().ok_or_else(|| RpcError::new(INVALID_PARAMS, "verbosity must be an integer"))?,
+                };
+                let blockchain = self.blockchain.lock().unwrap();
+                let block = blockchain.get_block(hash).map_err(|e| RpcError::new(INVALID_ADDRESS_OR_KEY, e))?;
+                if verbosity == 0 {
+                    return Ok(json!(hex::encode(bincode::serialize(&block).unwrap())));
+                }
+                let view = BlockView::new(&blockchain, hash).map_err(|e| RpcError::new(INVALID_ADDRESS_OR_KEY, e))?;
+                Ok(serde_json::to_value(view).unwrap())
+            }
+            "getrawmempool" => {
+                check_param_count(params, 0)?;
+                let mempool = self.mempool.lock().unwrap();
+                Ok(json!(mempool.pending_hashes().iter().map(|h| h.to_string()).collect::<Vec<String>>()))
+            }
+            "sendrawtransaction" => {
+                check_param_count(params, 1)?;
+                let hex_string: String = string_param(params, 0, "hexstring")?;
+                let bytes = hex::decode(hex_string.trim()).map_err(|e| RpcError::new(DESERIALIZATION_ERROR, format!("invalid hex: {}", e)))?;
+                let trx: SignedTransaction = bincode::deserialize(&bytes)
+                    .map_err(|e| RpcError::new(DESERIALIZATION_ERROR, format!("can not decode transaction: {}", e)))?;
+                let hash = trx.hash();
+                let blockchain = self.blockchain.lock().unwrap();
+                if blockchain.find_transaction(hash).is_ok() {
+                    return Err(RpcError::new(VERIFY_ALREADY_IN_CHAIN, "transaction already in block chain"));
+                }
+                let mut mempool = self.mempool.lock().unwrap();
+                // sending a transaction again is not an error, it is only broadcast once
+                if !mempool.is_hash_present(hash) {
+                    let tip_state = blockchain.get_state(blockchain.tip()).unwrap();
+                    mempool.insert(&trx, Some(&tip_state)).map_err(|e| RpcError::new(VERIFY_REJECTED, e))?;
+                    drop(mempool);
+                    drop(blockchain);
+                    self.network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                }
+                Ok(json!(hash.to_string()))
+            }
+            "getpeerinfo" => {
+                check_param_count(params, 0)?;
+                let peers: Vec<Value> = self.network.peers().iter().map(|peer| json!({
+                    "addr": peer.addr().to_string(),
+                    "inbound": peer.direction() == Direction::Incoming,
+                })).collect();
+                Ok(Value::Array(peers))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method {} not found", method))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::server::TestReceiver;
+    use crate::types::address::Address;
+    use crate::types::amount::Amount;
+    use crate::types::key_pair;
+    use crate::types::transaction::Transaction;
+
+    fn node() -> (Node, TestReceiver) {
+        let (network, receiver) = NetworkServerHandle::new_for_test();
+        let node = Node{
+            blockchain: Arc::new(Mutex::new(Blockchain::new())),
+            mempool: Arc::new(Mutex::new(TransactionMempool::new())),
+            network,
+        };
+        (node, receiver)
+    }
+
+    fn call(node: &Node, method: &str, params: Value) -> Value {
+        node.handle(&json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 7}).to_string()).unwrap()
+    }
+
+    #[test]
+    fn json_rpc_methods() {
+        let (node, _receiver) = node();
+        assert_eq!(call(&node, "getblockcount", json!([])), json!({"jsonrpc": "2.0", "result": 0, "id": 7}));
+        let genesis_hash = node.blockchain.lock().unwrap().tip();
+        let block = call(&node, "getblock", json!([genesis_hash.to_string()]));
+        assert_eq!(block["result"]["height"], json!(0));
+        assert_eq!(call(&node, "getblock", json!([H256::default().to_string()]))["error"]["code"], json!(INVALID_ADDRESS_OR_KEY));
+        assert_eq!(call(&node, "getblock", json!([]))["error"]["code"], json!(INVALID_PARAMS));
+
+        let receiver = Address::from_public_key_bytes(&[1u8; 32]);
+        let trx = Transaction{receiver, value: Amount::from(10), fee: Amount::ZERO, account_nonce: 1, lock_height: None, lock_time: None};
+        let signed_trx = SignedTransaction::new(trx, &key_pair::from_seed(0));
+        let raw = hex::encode(bincode::serialize(&signed_trx).unwrap());
+        assert_eq!(call(&node, "sendrawtransaction", json!([raw]))["result"], json!(signed_trx.hash().to_string()));
+        assert_eq!(call(&node, "getrawmempool", json!([]))["result"], json!([signed_trx.hash().to_string()]));
+        assert_eq!(call(&node, "sendrawtransaction", json!(["00"]))["error"]["code"], json!(DESERIALIZATION_ERROR));
+
+        assert_eq!(call(&node, "getblockchaininfo", json!([]))["error"]["code"], json!(METHOD_NOT_FOUND));
+        assert_eq!(node.handle("{").unwrap()["error"]["code"], json!(PARSE_ERROR));
+        assert_eq!(node.handle(r#"{"method": "getblockcount", "id": 1}"#).unwrap()["error"]["code"], json!(INVALID_REQUEST));
+        assert_eq!(node.handle(r#"{"jsonrpc": "2.0", "method": "getblockcount"}"#), None);
+        let batch = node.handle(r#"[{"jsonrpc": "2.0", "method": "getblockcount", "id": 1},
+            {"jsonrpc": "2.0", "method": "getblockcount"}, {"jsonrpc": "2.0", "method": "nope", "id": 2}]"#).unwrap();
+        assert_eq!(batch.as_array().unwrap().len(), 2);
+        assert_eq!(batch[1]["error"]["code"], json!(METHOD_NOT_FOUND));
+    }
+
+    #[test]
+    fn access_by_host_and_password() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut access = Access::default();
+        assert_eq!(access.check(local, None), Ok(()));
+        assert_eq!(access.check(remote, None), Err(Denied::Forbidden));
+
+        access.allow_ip.push(remote);
+        access.password = Some(String::from("secret"));
+        let basic = |credentials: &str| format!("Basic {}", BASE64.encode(credentials.as_bytes()));
+        assert_eq!(access.check(remote, Some(&basic("user:secret"))), Ok(()));
+        assert_eq!(access.check(local, Some(&basic(":secret"))), Ok(()));
+        assert_eq!(access.check(remote, Some(&basic("user:wrong"))), Err(Denied::Unauthorized));
+        assert_eq!(access.check(local, Some(&basic("secret"))), Err(Denied::Unauthorized));
+        assert_eq!(access.check(local, None), Err(Denied::Unauthorized));
+        assert_eq!(access.check("10.0.0.3".parse().unwrap(), Some(&basic("user:secret"))), Err(Denied::Forbidden));
+    }
+}
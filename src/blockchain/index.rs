@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+
+/// Transaction hash -> (block hash, position in the block), for the blocks of the longest chain
+#[derive(Debug, Default, Clone)]
+pub struct TxIndex {
+	map: HashMap<H256, (H256, usize)>,
+}
+
+impl TxIndex {
+	pub fn get(&self, hash: &H256) -> Option<(H256, usize)> {
+		self.map.get(hash).copied()
+	}
+
+	/// Index the transactions of `block`, which became part of the longest chain
+	pub fn connect(&mut self, hash: H256, block: &Block) {
+		for (position, trx) in block.content.data.iter().enumerate() {
+			self.map.insert(trx.hash(), (hash, position));
+		}
+	}
+
+	/// Forget the transactions of `block`, which left the longest chain in a reorg
+	pub fn disconnect(&mut self, block: &Block) {
+		for trx in block.content.data.iter() {
+			self.map.remove(&trx.hash());
+		}
+	}
+}
+
+/// Address -> (block hash, position in the block) of the transactions sending or receiving coins,
+/// ordered from genesis to the tip of the longest chain
+#[derive(Debug, Default, Clone)]
+pub struct AddressIndex {
+	map: HashMap<Address, Vec<(H256, usize)>>,
+}
+
+impl AddressIndex {
+	pub fn get(&self, address: &Address) -> &[(H256, usize)] {
+		self.map.get(address).map_or(&[], |v| v.as_slice())
+	}
+
+	/// Index the transactions of `block`, which became the tip of the longest chain
+	pub fn connect(&mut self, hash: H256, block: &Block) {
+		for (position, trx) in block.content.data.iter().enumerate() {
+			let (sender, receiver) = (trx.sender_address(), trx.receiver_address());
+			self.map.entry(sender).or_default().push((hash, position));
+			if receiver != sender {
+				self.map.entry(receiver).or_default().push((hash, position));
+			}
+		}
+	}
+
+	/// Forget the transactions of `block`, the tip of the longest chain, which left it in a reorg.
+	/// They are the last ones of their addresses.
+	pub fn disconnect(&mut self, block: &Block) {
+		for trx in block.content.data.iter().rev() {
+			let (sender, receiver) = (trx.sender_address(), trx.receiver_address());
+			for address in [sender, receiver].iter() {
+				if let Some(transactions) = self.map.get_mut(address) {
+					transactions.pop();
+					if transactions.is_empty() {
+						self.map.remove(address);
+					}
+				}
+				if receiver == sender {
+					break;
+				}
+			}
+		}
+	}
+}
@@ -1,23 +1,32 @@
 #![allow(unused)]
+pub mod index;
+pub mod spec;
+pub mod time;
+
 use serde::{Serialize, Deserialize};
-use crate::types::block::{Block, generate_genesis_block};
+use crate::types::block::Block;
 use crate::types::hash::{H256, Hashable};
 use std::collections::HashMap;
 use crate::types::transaction::{Transaction, SignedTransaction, generate_random_transaction};
 use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::error::{BlockError, StorageError, TransactionError};
 use crate::types::key_pair;
 use rand::Rng;
 use ring::signature::{KeyPair, Ed25519KeyPair};
+use time::NetworkTime;
+use spec::{ChainSpec, ConsensusParams};
+use index::{TxIndex, AddressIndex};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct State{
-	ledger: HashMap<Address, (u32, u32)>, // Address corresponding to public_key -> (account_nonce, balance)
+	ledger: HashMap<Address, (u32, Amount)>, // Address corresponding to public_key -> (account_nonce, balance)
 }
 
 impl State {
     /// Create a new state containing balances defined as per Initial Coin Offering
-    pub fn new(ico: Vec<(Address, (u32, u32))>) -> Self {
-		let mut ledger: HashMap< Address, (u32, u32)> = HashMap::new();
+    pub fn new(ico: Vec<(Address, (u32, Amount))>) -> Self {
+		let mut ledger: HashMap< Address, (u32, Amount)> = HashMap::new();
 		for entry in ico.iter() {
 			let (addr, tuple) = entry;
 			ledger.insert(*addr, *tuple);
@@ -30,37 +39,48 @@ impl State {
 		return state;
 	}
 
-	/// Return an updated state after applying a vector of transactions to it
-	pub fn update(&mut self, signed_trx: &Vec<SignedTransaction>) -> Result<State, &'static str> {
+	/// Return an updated state after applying a vector of transactions, included in a block at `height` whose parent
+	/// has the median time past `median_time_past`
+	pub fn update(&mut self, signed_trx: &Vec<SignedTransaction>, height: u32, median_time_past: u128) -> Result<State, TransactionError> {
 		let mut state: State = self.clone();
-		let mut tuple: (u32, u32);
-		let mut val: u32;
+		let mut tuple: (u32, Amount);
+		let mut val: Amount;
 		let mut sender_addr: Address;
 		let mut receiver_addr: Address;
 
 		for strx in signed_trx.iter() {
+			if !strx.verify() {
+				return Err(TransactionError::InvalidSignature);
+			}
+			if !strx.is_final(height, median_time_past) {
+				return Err(TransactionError::NotFinal);
+			}
 			sender_addr = strx.sender_address();
 			if !state.ledger.contains_key(&sender_addr) {
-				return Err("Invalid Transaction: sender does not exist");
+				return Err(TransactionError::UnknownSender);
 			}
 			tuple = state.ledger[&sender_addr];
-			let (nonce, mut bal) = tuple;
+			let (nonce, bal) = tuple;
 			val = strx.value();
-			if bal<val {
-				return Err("Invalid Transaction: insufficient balance at sender");
-			} else if nonce+1!=strx.account_nonce() {
-				return Err("Invalid Transaction: invalid account nonce");
+			let next_nonce = match nonce.checked_add(1) {
+				Some(n) => n,
+				None => return Err(TransactionError::NonceOverflow),
+			};
+			let sender_bal = match val.checked_add(strx.fee()).and_then(|total| bal.checked_sub(total)) {
+				Some(b) => b,
+				None => return Err(TransactionError::InsufficientBalance),
+			};
+			if next_nonce!=strx.account_nonce() {
+				return Err(TransactionError::InvalidNonce{expected: next_nonce, found: strx.account_nonce()});
 			}
-			state.ledger.insert(sender_addr, (nonce+1, bal-val));
+			state.ledger.insert(sender_addr, (next_nonce, sender_bal));
 
 			receiver_addr = strx.receiver_address();
-			if !state.ledger.contains_key(&receiver_addr) {
-				state.ledger.insert(receiver_addr, (0, val));
-			} else {
-				tuple = state.ledger[ &receiver_addr ];
-				let (nonce, bal) = tuple;
-				state.ledger.insert(receiver_addr, (nonce, bal+val));
-			}
+			let (nonce, bal) = state.ledger.get(&receiver_addr).copied().unwrap_or((0, Amount::ZERO));
+			match bal.checked_add(val) {
+				Some(b) => state.ledger.insert(receiver_addr, (nonce, b)),
+				None => return Err(TransactionError::BalanceOverflow),
+			};
 		}
 
 		return Ok(state);
@@ -77,22 +97,29 @@ impl State {
 		}
 	}
 
+	/// Addresses in the ledger, sorted by their bytes
+	pub fn addresses(&self) -> Vec<Address> {
+		let mut addresses: Vec<Address> = self.ledger.keys().copied().collect();
+		addresses.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+		addresses
+	}
+
 	/// Fetch the details corresponding to an address
-	pub fn get_balance(&self, addr: Address) -> Result<(u32, u32), &'static str> {
+	pub fn get_balance(&self, addr: Address) -> Result<(u32, Amount), StorageError> {
 		if self.ledger.contains_key(&addr) {
 			return Ok(self.ledger[ &addr ]);
 		} else {
-			return Err("Address does not exist in state");
+			return Err(StorageError::AddressNotFound);
 		}
 	}
 
-	/// Return the account details as a vector of tuples
-	pub fn get_account_details(&self) -> Vec<(String, String, String)> {
+	/// Return the account details as a vector of tuples, with addresses encoded with the version byte `address_version`
+	pub fn get_account_details(&self, address_version: u8) -> Vec<(String, String, String)> {
 		let mut accounts: Vec<(String, String, String)> = vec![];
 		let mut account_tuple;
 		for (key,value) in &self.ledger {
-			if value.1>0 {
-				account_tuple = (key.to_string(), value.0.to_string(), value.1.to_string());
+			if !value.1.is_zero() {
+				account_tuple = (key.encode(address_version).to_string(), value.0.to_string(), value.1.to_string());
 				accounts.push(account_tuple);
 			}
 		}
@@ -101,82 +128,217 @@ impl State {
 }
 
 
+/// Default maximum size of a serialized block in bytes
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+/// Default number of blocks, ending at the parent, whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// Default of how far (in ms) a block timestamp may be ahead of the network-adjusted time
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// Checks that only depend on the block itself: proof of work, merkle root, size and transaction signatures
+pub fn check_block(block: &Block, consensus: &ConsensusParams) -> Result<(), BlockError> {
+	if block.hash() > block.get_difficulty() {
+		return Err(BlockError::InvalidProofOfWork);
+	}
+	if block.compute_merkle_root() != block.header.merkle_root {
+		return Err(BlockError::MerkleRootMismatch);
+	}
+	let size = block.size();
+	if size > consensus.max_block_size {
+		return Err(BlockError::BlockTooLarge(size));
+	}
+	for trx in block.content.data.iter() {
+		if !trx.verify() {
+			return Err(BlockError::InvalidTransaction(TransactionError::InvalidSignature));
+		}
+	}
+	Ok(())
+}
+
 // TODO - convert serialisation function to bincode
 pub struct Blockchain {
 	hashmap: HashMap< H256, (Block, u32)>, 	// storage of blocks, HashMap: Hash -> (Block, height)
 	longest_chain_len: u32, 				// length of longest chain
 	tip: H256, 								// hash of last block in longest chain
+	main_chain: Vec<H256>,					// hashes of the blocks of the longest chain, by height
+	tx_index: Option<TxIndex>,				// where the transactions of the longest chain are, if enabled
+	address_index: Option<AddressIndex>,	// transactions of the longest chain by address, if enabled
 	block_state_map: HashMap<H256, State>,	// storage of states, HashMap: Hash -> State
+	network_time: NetworkTime,				// clock used to reject blocks from the future
+	consensus: ConsensusParams,				// rules of the chain spec
+	address_version: u8,					// version byte of the addresses of the chain
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the development chain, only containing the genesis block
     pub fn new() -> Self {
-		//let parent: H256 = hex!("0000000000000000000000000000000000000000000000000000000000000000").into();
-		//let genesis: Block = generate_random_block(&parent);
-		let genesis: Block = generate_genesis_block();
+		Self::with_spec(&ChainSpec::development())
+    }
+
+	/// Create a new blockchain of the chain defined by `spec`, only containing its genesis block
+	pub fn with_spec(spec: &ChainSpec) -> Self {
+		let genesis: Block = spec.genesis_block();
 		let hash: H256 = genesis.hash();
 
 		let mut hashmap = HashMap::new();
 		hashmap.insert(hash, (genesis, 0));
-
-		let mut ico: Vec<(Address, (u32, u32))> = vec![];
-		let mut key: Ed25519KeyPair;
-		let mut bal: u32;
-		let mut addr: Address;
-
-		key = key_pair::from_seed(0);
-		bal = 1e6 as u32;
-		addr = Address::from_public_key_bytes(key.public_key().as_ref());
-		//println!("ico public key: {:#?}", key.public_key().as_ref());
-		//println!("ico addr: {}", addr);
-		ico.push((addr, (0, bal)));
-
-		key = key_pair::from_seed(1);
-		bal = 0;
-		addr = Address::from_public_key_bytes(key.public_key().as_ref());
-		ico.push((addr, (0, bal)));
-		key = key_pair::from_seed(2);
-		bal = 0;
-		addr = Address::from_public_key_bytes(key.public_key().as_ref());
-		ico.push((addr, (0, bal)));
-
-		let state = State::new(ico);
 		let mut block_state_map = HashMap::new();
-		block_state_map.insert(hash, state);
+		block_state_map.insert(hash, spec.genesis_state());
 
-		let chain = Self {
-			hashmap: hashmap,
+		Self {
+			hashmap,
 			longest_chain_len: 0,
 			tip: hash,
-			block_state_map: block_state_map,
-		};
-		return chain;
-    }
+			main_chain: vec![hash],
+			tx_index: None,
+			address_index: None,
+			block_state_map,
+			network_time: NetworkTime::new(),
+			consensus: spec.consensus.clone(),
+			address_version: spec.address_version,
+		}
+	}
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
+    /// Insert a block into blockchain. Nothing is stored if the block is invalid.
+    pub fn insert(&mut self, block: &Block) -> Result<(), BlockError> {
 		let hash: H256 = block.hash();
+		let (height, state) = self.validate(block)?;
+
+		self.hashmap.insert(hash, (block.clone(), height));
+		self.block_state_map.insert(hash, state);
+		if height > self.longest_chain_len {
+			self.longest_chain_len = height;
+			self.tip = hash;
+			self.update_main_chain(hash);
+		}
+		Ok(())
+	}
+
+	/// Make the block `tip` the end of the main chain index. On a reorg, the blocks after the fork point
+	/// are replaced by the new branch, and the transaction indexes are rewound before the branch is indexed.
+	fn update_main_chain(&mut self, tip: H256) {
+		let mut branch: Vec<H256> = vec![];
+		let mut hash = tip;
+		let fork_height = loop {
+			let (block, height) = &self.hashmap[ &hash ];
+			if self.main_chain.get(*height as usize) == Some(&hash) {
+				break *height as usize;
+			}
+			branch.push(hash);
+			hash = block.get_parent();
+		};
+		for hash in self.main_chain.split_off(fork_height + 1).iter().rev() {
+			let (block, _height) = &self.hashmap[ hash ];
+			if let Some(index) = self.tx_index.as_mut() {
+				index.disconnect(block);
+			}
+			if let Some(index) = self.address_index.as_mut() {
+				index.disconnect(block);
+			}
+		}
+		for hash in branch.into_iter().rev() {
+			let (block, _height) = &self.hashmap[ &hash ];
+			if let Some(index) = self.tx_index.as_mut() {
+				index.connect(hash, block);
+			}
+			if let Some(index) = self.address_index.as_mut() {
+				index.connect(hash, block);
+			}
+			self.main_chain.push(hash);
+		}
+	}
+
+	/// Index the transactions of the longest chain by hash, for `find_transaction`
+	pub fn enable_tx_index(&mut self) {
+		let mut index = TxIndex::default();
+		for hash in self.main_chain.iter() {
+			index.connect(*hash, &self.hashmap[ hash ].0);
+		}
+		self.tx_index = Some(index);
+	}
+
+	/// Index the transactions of the longest chain by address, for `address_transactions`
+	pub fn enable_address_index(&mut self) {
+		let mut index = AddressIndex::default();
+		for hash in self.main_chain.iter() {
+			index.connect(*hash, &self.hashmap[ hash ].0);
+		}
+		self.address_index = Some(index);
+	}
+
+	/// Run every validation rule on a block, returning its height and the state after it.
+	/// Context-free checks come first, so that an orphan block is only reported as such once it is
+	/// known to be well formed.
+	pub fn validate(&self, block: &Block) -> Result<(u32, State), BlockError> {
+		check_block(block, &self.consensus)?;
+
 		let parent_hash: H256 = block.get_parent();
-		if hash > block.get_difficulty() {
-			println!("received invalid block, ignoring it. block: {:#?}", block);
-		} else if !self.hashmap.contains_key(&parent_hash) {
-			println!("received orphan block");
-			// add block to orphan buffer
-		} else { // block is valid
-			let parent_tuple = &self.hashmap[ &parent_hash ];
-			let (_parent, parent_height) = parent_tuple;
-			let height = parent_height + 1;
-			self.hashmap.insert(hash, (block.clone(), height));
-			if height > self.longest_chain_len {
-				self.longest_chain_len = height;
-				self.tip = hash;
+		let (parent, parent_height) = match self.hashmap.get(&parent_hash) {
+			Some(tuple) => tuple,
+			None => return Err(BlockError::UnknownParent(parent_hash)), // orphan block
+		};
+		let expected = self.expected_difficulty(parent);
+		if block.get_difficulty() != expected {
+			return Err(BlockError::DifficultyMismatch{expected, found: block.get_difficulty()});
+		}
+		let median_time_past = match self.median_time_past(parent_hash) {
+			Ok(t) => t,
+			Err(_) => return Err(BlockError::UnknownParent(parent_hash)),
+		};
+		if block.header.timestamp <= median_time_past {
+			return Err(BlockError::TimestampTooOld{median_time_past});
+		}
+		let max = self.network_time.now() + self.consensus.max_future_block_time;
+		if block.header.timestamp > max {
+			return Err(BlockError::TimestampTooNew{max});
+		}
+
+		let height = parent_height + 1;
+		let mut parent_state: State = match self.block_state_map.get(&parent_hash) {
+			Some(state) => state.clone(),
+			None => return Err(BlockError::UnknownParent(parent_hash)),
+		};
+		let state: State = parent_state.update(&block.content.data, height, median_time_past)?;
+		Ok((height, state))
+	}
+
+	/// Median timestamp of the block `hash` and its ancestors, up to the median time span of the chain.
+	/// A child of `hash` must have a larger timestamp.
+	pub fn median_time_past(&self, hash: H256) -> Result<u128, StorageError> {
+		let mut timestamps: Vec<u128> = vec![];
+		let mut tuple: &(Block, u32) = match self.hashmap.get(&hash) {
+			Some(tuple) => tuple,
+			None => return Err(StorageError::BlockNotFound(hash)),
+		};
+		loop {
+			timestamps.push(tuple.0.header.timestamp);
+			if tuple.1 == 0 || timestamps.len() == self.consensus.median_time_span {
+				break;
 			}
-			// Process and save the state of current block
-			let mut parent_state: State = self.block_state_map[ &parent_hash ].clone();
-			let state: State = parent_state.update(&block.content.data).unwrap();
-			self.block_state_map.insert(hash, state);
+			tuple = &self.hashmap[ &tuple.0.get_parent() ];
 		}
+		timestamps.sort_unstable();
+		Ok(timestamps[timestamps.len() / 2])
+	}
+
+	/// Consensus rules of the chain
+	pub fn consensus(&self) -> &ConsensusParams {
+		&self.consensus
+	}
+
+	/// Version byte of the encoded addresses of the chain
+	pub fn address_version(&self) -> u8 {
+		self.address_version
+	}
+
+	/// Clock used for block validation, adjusted with the offsets of the peers' clocks
+	pub fn network_time(&self) -> NetworkTime {
+		self.network_time.clone()
+	}
+
+	/// Difficulty that a child of `parent` must have. It is constant along the chain.
+	fn expected_difficulty(&self, parent: &Block) -> H256 {
+		parent.get_difficulty()
 	}
 
     /// Get the last block's hash of the longest chain
@@ -186,21 +348,7 @@ impl Blockchain {
 
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-		let mut longest_chain: Vec<H256> = Vec::new();
-		let mut hash: H256 = self.tip;
-		let (mut block, mut height) = self.hashmap[ &hash ].clone();
-		let mut tuple: &(Block, u32);
-		while height>0 {
-			longest_chain.push(hash);
-			hash = block.get_parent();
-			tuple = &self.hashmap[ &hash ];
-			block = tuple.0.clone();
-			height = tuple.1;
-		}
-		longest_chain.push(hash);
-
-		longest_chain.reverse();
-		return longest_chain;
+		self.main_chain.clone()
     }
 
 	/// Check if a block hash is present
@@ -209,9 +357,9 @@ impl Blockchain {
 	}
 
 	/// Retrieve a block corresponding to a hash
-	pub fn get_block(&self, hash: H256) -> Result<Block, &'static str> {
+	pub fn get_block(&self, hash: H256) -> Result<Block, StorageError> {
 		if !self.hashmap.contains_key(&hash) {
-			return Err("invalid block hash");
+			return Err(StorageError::BlockNotFound(hash));
 		} else {
 			let tuple = &self.hashmap[ &hash ];
 			let (block, _height) = tuple;
@@ -219,10 +367,100 @@ impl Blockchain {
 		}
 	}
 
+	/// Retrieve the height of a block corresponding to a hash
+	pub fn get_height(&self, hash: H256) -> Result<u32, StorageError> {
+		match self.hashmap.get(&hash) {
+			Some((_block, height)) => Ok(*height),
+			None => Err(StorageError::BlockNotFound(hash)),
+		}
+	}
+
+	/// Whether the block `ancestor` is on the chain ending at the block `descendant`, which includes itself
+	pub fn is_ancestor(&self, ancestor: H256, descendant: H256) -> bool {
+		let ancestor_height = match self.hashmap.get(&ancestor) {
+			Some((_block, height)) => *height,
+			None => return false,
+		};
+		let mut hash = descendant;
+		while let Some((block, height)) = self.hashmap.get(&hash) {
+			if *height <= ancestor_height {
+				return hash == ancestor;
+			}
+			hash = block.get_parent();
+		}
+		false
+	}
+
+	/// Hash of the block at `height` in the longest chain
+	pub fn hash_at_height(&self, height: u32) -> Result<H256, StorageError> {
+		match self.main_chain.get(height as usize) {
+			Some(hash) => Ok(*hash),
+			None => Err(StorageError::HeightNotFound(height)),
+		}
+	}
+
+	/// Blocks from the fork point to `old_tip`, a former tip that a reorg took out of the longest chain.
+	/// Empty while `old_tip` is in the longest chain.
+	pub fn disconnected_blocks(&self, old_tip: H256) -> Vec<Block> {
+		let mut blocks: Vec<Block> = vec![];
+		let mut hash = old_tip;
+		while let Some((block, height)) = self.hashmap.get(&hash) {
+			if self.main_chain.get(*height as usize) == Some(&hash) {
+				break;
+			}
+			blocks.push(block.clone());
+			hash = block.get_parent();
+		}
+		blocks.reverse();
+		blocks
+	}
+
+	/// Number of blocks of the longest chain from the block `hash` to the tip, both included.
+	/// Blocks outside the longest chain have no confirmations.
+	pub fn confirmations(&self, hash: H256) -> u32 {
+		match self.hashmap.get(&hash) {
+			Some((_block, height)) if self.main_chain.get(*height as usize) == Some(&hash) => self.longest_chain_len - height + 1,
+			_ => 0,
+		}
+	}
+
+	/// Find a transaction of the longest chain, returning the hash of its block and its position in it.
+	/// Without the transaction index, the longest chain is scanned from the tip.
+	pub fn find_transaction(&self, hash: H256) -> Result<(H256, usize), StorageError> {
+		if let Some(index) = &self.tx_index {
+			return index.get(&hash).ok_or(StorageError::TransactionNotFound(hash));
+		}
+		for block_hash in self.main_chain.iter().rev() {
+			let (block, _height) = &self.hashmap[ block_hash ];
+			if let Some(position) = block.content.data.iter().position(|trx| trx.hash() == hash) {
+				return Ok((*block_hash, position));
+			}
+		}
+		Err(StorageError::TransactionNotFound(hash))
+	}
+
+	/// Transactions of the longest chain sent or received by `address`, as (block hash, position),
+	/// ordered from genesis to the tip. Without the address index, the whole longest chain is scanned.
+	pub fn address_transactions(&self, address: Address) -> Vec<(H256, usize)> {
+		if let Some(index) = &self.address_index {
+			return index.get(&address).to_vec();
+		}
+		let mut transactions: Vec<(H256, usize)> = vec![];
+		for block_hash in self.main_chain.iter() {
+			let (block, _height) = &self.hashmap[ block_hash ];
+			for (position, trx) in block.content.data.iter().enumerate() {
+				if trx.sender_address() == address || trx.receiver_address() == address {
+					transactions.push((*block_hash, position));
+				}
+			}
+		}
+		transactions
+	}
+
 	/// Retrieve the state corresponding to a block hash
-	pub fn get_state(&self, hash: H256) -> Result<State, &'static str> {
+	pub fn get_state(&self, hash: H256) -> Result<State, StorageError> {
 		if !self.block_state_map.contains_key(&hash) {
-			return Err("invalid block hash");
+			return Err(StorageError::StateNotFound(hash));
 		} else {
 			let state: State = self.block_state_map[ &hash ].clone();
 			return Ok(state.clone());
@@ -231,62 +469,22 @@ impl Blockchain {
 
     /// Get all transactions' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_transactions_in_longest_chain(&self) -> Vec<Vec<H256>> {
-		let mut longest_chain: Vec<Vec<H256>> = Vec::new();
-		let mut hash: H256 = self.tip;
-		let (mut block, mut height) = self.hashmap[ &hash ].clone();
-		let mut tuple: &(Block, u32);
-		while height>0 {
-			longest_chain.push(block.get_transaction_hashes());
-			hash = block.get_parent();
-			tuple = &self.hashmap[ &hash ];
-			block = tuple.0.clone();
-			height = tuple.1;
-		}
-		longest_chain.push(block.get_transaction_hashes());
-
-		longest_chain.reverse();
-		return longest_chain;
+		self.main_chain.iter().map(|hash| self.hashmap[ hash ].0.get_transaction_hashes()).collect()
     }
 
     /// Count the number of transactions in the longest chain
     pub fn count_transactions_in_longest_chain(&self) -> usize {
-		let mut longest_chain_count: usize = 0;
-		let mut hash: H256 = self.tip;
-		let (mut block, mut height) = self.hashmap[ &hash ].clone();
-		let mut tuple: &(Block, u32);
-		while height>0 {
-			longest_chain_count += block.content.data.len();
-			hash = block.get_parent();
-			tuple = &self.hashmap[ &hash ];
-			block = tuple.0.clone();
-			height = tuple.1;
-		}
-
-		return longest_chain_count;
+		self.main_chain.iter().map(|hash| self.hashmap[ hash ].0.content.data.len()).sum()
     }
 
 	/// Returns vector of accounts
 	/// TODO convert Address, nonce, balance into string
-	pub fn get_block_state(&self, id: u32) -> Vec<(String, String, String)> {
-		let mut hash: H256 = self.tip;
-		let (mut block, mut height) = self.hashmap[ &hash ].clone();
-		let mut accounts: Vec<(String, String, String)> = vec![];
-		let mut tuple: &(Block, u32);
-
-		if height<id {
-			return accounts;
-		}
-		while height>id{
-			hash = block.get_parent();
-			tuple = &self.hashmap[ &hash ];
-			block = tuple.0.clone();
-			height = tuple.1;
-		}
-
+	pub fn get_block_state(&self, id: u32) -> Result<Vec<(String, String, String)>, StorageError> {
+		let hash: H256 = self.hash_at_height(id)?;
 		let state: State = self.block_state_map[ &hash ].clone();
-		let mut accounts: Vec<(String, String, String)> = state.get_account_details();
+		let mut accounts: Vec<(String, String, String)> = state.get_account_details(self.address_version);
 		accounts.sort();
-		return accounts;
+		Ok(accounts)
 	}
 }
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -302,7 +500,7 @@ mod tests {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        blockchain.insert(&block).unwrap();
         assert_eq!(blockchain.tip(), block.hash());
     }
 	#[test]
@@ -321,17 +519,17 @@ mod tests {
 		let mut blockchain = Blockchain::new();
 		let genesis_hash = blockchain.tip();
 		let b1 = generate_random_block(&genesis_hash);
-		blockchain.insert(&b1);
-		let b2 = generate_random_block(&b1.hash());
-		blockchain.insert(&b2);
-		let b3 = generate_random_block(&b2.hash());
-		blockchain.insert(&b3);
-		let b4 = generate_random_block(&b1.hash());
-		blockchain.insert(&b4);
-		let b5 = generate_random_block(&b4.hash());
-		blockchain.insert(&b5);
+		blockchain.insert(&b1).unwrap();
+		let b2 = child_of(&b1);
+		blockchain.insert(&b2).unwrap();
+		let b3 = child_of(&b2);
+		blockchain.insert(&b3).unwrap();
+		let b4 = child_of(&b1);
+		blockchain.insert(&b4).unwrap();
+		let b5 = child_of(&b4);
+		blockchain.insert(&b5).unwrap();
 		let b6 = generate_random_block(&genesis_hash);
-		blockchain.insert(&b6);
+		blockchain.insert(&b6).unwrap();
 
 		assert_eq!(blockchain.tip(), b3.hash());
     }
@@ -353,19 +551,19 @@ mod tests {
 		let mut blockchain = Blockchain::new();
 		let genesis_hash = blockchain.tip();
 		let b1 = generate_random_block(&genesis_hash);
-		blockchain.insert(&b1);
-		let b2 = generate_random_block(&b1.hash());
-		blockchain.insert(&b2);
-		let b3 = generate_random_block(&b2.hash());
-		blockchain.insert(&b3);
-		let b4 = generate_random_block(&b1.hash());
-		blockchain.insert(&b4);
-		let b5 = generate_random_block(&b4.hash());
-		blockchain.insert(&b5);
+		blockchain.insert(&b1).unwrap();
+		let b2 = child_of(&b1);
+		blockchain.insert(&b2).unwrap();
+		let b3 = child_of(&b2);
+		blockchain.insert(&b3).unwrap();
+		let b4 = child_of(&b1);
+		blockchain.insert(&b4).unwrap();
+		let b5 = child_of(&b4);
+		blockchain.insert(&b5).unwrap();
 		let b6 = generate_random_block(&genesis_hash);
-		blockchain.insert(&b6);
-		let b7 = generate_random_block(&b5.hash());
-		blockchain.insert(&b7);
+		blockchain.insert(&b6).unwrap();
+		let b7 = child_of(&b5);
+		blockchain.insert(&b7).unwrap();
 
 		assert_eq!(blockchain.tip(), b7.hash());
     }
@@ -387,19 +585,19 @@ mod tests {
 		let mut blockchain = Blockchain::new();
 		let genesis_hash = blockchain.tip();
 		let b1 = generate_random_block(&genesis_hash);
-		blockchain.insert(&b1);
-		let b2 = generate_random_block(&b1.hash());
-		blockchain.insert(&b2);
-		let b3 = generate_random_block(&b2.hash());
-		blockchain.insert(&b3);
-		let b4 = generate_random_block(&b1.hash());
-		blockchain.insert(&b4);
-		let b5 = generate_random_block(&b4.hash());
-		blockchain.insert(&b5);
+		blockchain.insert(&b1).unwrap();
+		let b2 = child_of(&b1);
+		blockchain.insert(&b2).unwrap();
+		let b3 = child_of(&b2);
+		blockchain.insert(&b3).unwrap();
+		let b4 = child_of(&b1);
+		blockchain.insert(&b4).unwrap();
+		let b5 = child_of(&b4);
+		blockchain.insert(&b5).unwrap();
 		let b6 = generate_random_block(&genesis_hash);
-		blockchain.insert(&b6);
-		let b7 = generate_random_block(&b5.hash());
-		blockchain.insert(&b7);
+		blockchain.insert(&b6).unwrap();
+		let b7 = child_of(&b5);
+		blockchain.insert(&b7).unwrap();
 
 		let longest_chain: Vec<H256> = blockchain.all_blocks_in_longest_chain();
 
@@ -412,6 +610,201 @@ mod tests {
 			]
 		);
     }
+	/// Search a nonce for a block whose header was modified
+	fn solve(mut block: Block) -> Block {
+		while block.hash() > block.get_difficulty() {
+			block.header.nonce = block.header.nonce.wrapping_add(1);
+		}
+		block
+	}
+	/// A random block on top of `parent`, later than it: blocks generated within the same millisecond
+	/// would not be past the median time of their parent
+	fn child_of(parent: &Block) -> Block {
+		let mut block = generate_random_block(&parent.hash());
+		block.header.timestamp = block.header.timestamp.max(parent.header.timestamp + 1);
+		solve(block)
+	}
+	#[test]
+	fn validate_merkle_root_and_difficulty() {
+		let mut blockchain = Blockchain::new();
+		let genesis_hash = blockchain.tip();
+
+		let mut block = generate_random_block(&genesis_hash);
+		block.header.merkle_root = [1u8; 32].into();
+		assert_eq!(blockchain.insert(&solve(block)), Err(BlockError::MerkleRootMismatch));
+
+		let mut block = generate_random_block(&genesis_hash);
+		block.header.difficulty = hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+		let block = solve(block);
+		assert!(matches!(blockchain.insert(&block), Err(BlockError::DifficultyMismatch{..})));
+
+		let orphan = generate_random_block(&block.hash());
+		assert_eq!(blockchain.insert(&orphan), Err(BlockError::UnknownParent(block.hash())));
+		assert_eq!(blockchain.tip(), genesis_hash);
+	}
+	#[test]
+	fn multisig_spend_needs_threshold() {
+		let keys: Vec<Ed25519KeyPair> = (10..13).map(key_pair::from_seed).collect();
+		let public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key().as_ref().to_vec()).collect();
+		let multisig_addr = Address::from_multisig(2, &public_keys);
+		let mut state = State::new(vec![(multisig_addr, (0, Amount::from(100)))]);
+
+		let trx = Transaction{receiver: Address::from_public_key_bytes(&public_keys[0]), value: Amount::from(40), fee: Amount::ZERO, account_nonce: 1, lock_height: None, lock_time: None};
+		let mut signed_trx = SignedTransaction::new_multisig(trx, 2, &public_keys);
+		signed_trx.add_signature(&keys[1]).unwrap();
+		assert!(state.update(&vec![signed_trx.clone()], 1, 0).is_err());
+
+		signed_trx.add_signature(&keys[2]).unwrap();
+		let state = state.update(&vec![signed_trx], 1, 0).unwrap();
+		assert_eq!(state.get_balance(multisig_addr).unwrap(), (1, Amount::from(60)));
+	}
+	#[test]
+	fn balance_overflow_is_rejected() {
+		let sender = key_pair::from_seed(10);
+		let sender_addr = Address::from_public_key_bytes(sender.public_key().as_ref());
+		let receiver_addr = Address::from_public_key_bytes(key_pair::from_seed(11).public_key().as_ref());
+		let mut state = State::new(vec![(sender_addr, (0, Amount::from(10))), (receiver_addr, (0, Amount::MAX))]);
+
+		let trx = Transaction{receiver: receiver_addr, value: Amount::from(1), fee: Amount::ZERO, account_nonce: 1, lock_height: None, lock_time: None};
+		assert!(state.update(&vec![SignedTransaction::new(trx, &sender)], 1, 0).is_err());
+		let trx = Transaction{receiver: receiver_addr, value: Amount::from(11), fee: Amount::ZERO, account_nonce: 1, lock_height: None, lock_time: None};
+		assert!(state.update(&vec![SignedTransaction::new(trx, &sender)], 1, 0).is_err());
+	}
+	#[test]
+	fn ancestors_of_forks() {
+		let mut blockchain = Blockchain::new();
+		let genesis_hash = blockchain.tip();
+		let block_1 = generate_random_block(&genesis_hash);
+		let block_2 = child_of(&block_1);
+		let fork_1 = generate_random_block(&genesis_hash);
+		for block in [&block_1, &block_2, &fork_1].iter() {
+			blockchain.insert(block).unwrap();
+		}
+		assert!(blockchain.is_ancestor(genesis_hash, block_2.hash()));
+		assert!(blockchain.is_ancestor(block_1.hash(), block_2.hash()));
+		assert!(blockchain.is_ancestor(block_2.hash(), block_2.hash()));
+		assert!(!blockchain.is_ancestor(fork_1.hash(), block_2.hash()));
+		assert!(!blockchain.is_ancestor(block_2.hash(), block_1.hash()));
+	}
+	#[test]
+	fn block_timestamp_bounds() {
+		let mut blockchain = Blockchain::new();
+		let genesis_hash = blockchain.tip();
+		let block = generate_random_block(&genesis_hash);
+		blockchain.insert(&block).unwrap();
+		assert_eq!(blockchain.median_time_past(block.hash()).unwrap(), block.header.timestamp);
+
+		// median of the genesis (0) and the block: the timestamp of the block
+		let mut child = generate_random_block(&block.hash());
+		child.header.timestamp = 0;
+		assert!(matches!(blockchain.insert(&solve(child)), Err(BlockError::TimestampTooOld{..})));
+
+		let mut child = generate_random_block(&block.hash());
+		child.header.timestamp = time::system_time() + 2 * MAX_FUTURE_BLOCK_TIME;
+		assert!(matches!(blockchain.insert(&solve(child)), Err(BlockError::TimestampTooNew{..})));
+		assert_eq!(blockchain.tip(), block.hash());
+	}
+	#[test]
+	fn lock_time_follows_median_time_past() {
+		let mut blockchain = Blockchain::new();
+		let block = generate_random_block(&blockchain.tip());
+		blockchain.insert(&block).unwrap();
+		let median_time_past = blockchain.median_time_past(block.hash()).unwrap();
+
+		// a block timestamp past the lock time is not enough, the median time past of the parent must reach it
+		let ico = key_pair::from_seed(0);
+		let receiver = Address::from_public_key_bytes(key_pair::from_seed(1).public_key().as_ref());
+		for (lock_time, accepted) in [(median_time_past + 1, false), (median_time_past, true)].iter() {
+			let trx = Transaction{receiver, value: Amount::from(10), fee: Amount::from(1), account_nonce: 1, lock_height: None, lock_time: Some(*lock_time)};
+			let mut child = child_of(&block);
+			child.header.timestamp = median_time_past + 1000;
+			child.content.data = vec![SignedTransaction::new(trx, &ico)];
+			child.header.merkle_root = child.compute_merkle_root();
+			let result = blockchain.validate(&solve(child));
+			if *accepted {
+				assert!(result.is_ok());
+			} else {
+				assert!(matches!(result, Err(BlockError::InvalidTransaction(TransactionError::NotFinal))));
+			}
+		}
+	}
+	#[test]
+	fn main_chain_index_follows_reorgs() {
+		let mut blockchain = Blockchain::new();
+		let genesis_hash = blockchain.tip();
+		let ico = key_pair::from_seed(0);
+		let receiver = Address::from_public_key_bytes(key_pair::from_seed(1).public_key().as_ref());
+		let trx = Transaction{receiver, value: Amount::from(10), fee: Amount::from(1), account_nonce: 1, lock_height: None, lock_time: None};
+		let signed_trx = SignedTransaction::new(trx, &ico);
+		let mut block_1 = generate_random_block(&genesis_hash);
+		block_1.content.data = vec![signed_trx.clone()];
+		block_1.header.merkle_root = block_1.compute_merkle_root();
+		let block_1 = solve(block_1);
+		let block_2 = child_of(&block_1);
+		for block in [&block_1, &block_2].iter() {
+			blockchain.insert(block).unwrap();
+		}
+		assert_eq!(blockchain.hash_at_height(1), Ok(block_1.hash()));
+		assert_eq!(blockchain.confirmations(block_1.hash()), 2);
+		assert_eq!(blockchain.find_transaction(signed_trx.hash()), Ok((block_1.hash(), 0)));
+		assert_eq!(blockchain.address_transactions(receiver), vec![(block_1.hash(), 0)]);
+
+		// a longer fork without the transaction replaces both blocks
+		let fork_1 = generate_random_block(&genesis_hash);
+		let fork_2 = child_of(&fork_1);
+		let fork_3 = child_of(&fork_2);
+		for block in [&fork_1, &fork_2, &fork_3].iter() {
+			blockchain.insert(block).unwrap();
+		}
+		assert_eq!(blockchain.hash_at_height(1), Ok(fork_1.hash()));
+		assert_eq!(blockchain.hash_at_height(3), Ok(fork_3.hash()));
+		assert_eq!(blockchain.hash_at_height(4), Err(StorageError::HeightNotFound(4)));
+		assert_eq!(blockchain.confirmations(block_1.hash()), 0);
+		assert_eq!(blockchain.confirmations(fork_1.hash()), 3);
+		assert!(blockchain.find_transaction(signed_trx.hash()).is_err());
+		assert!(blockchain.address_transactions(receiver).is_empty());
+		let disconnected: Vec<H256> = blockchain.disconnected_blocks(block_2.hash()).iter().map(|b| b.hash()).collect();
+		assert_eq!(disconnected, vec![block_1.hash(), block_2.hash()]);
+		assert!(blockchain.disconnected_blocks(fork_2.hash()).is_empty());
+	}
+	#[test]
+	fn indexes_follow_reorgs() {
+		let ico = key_pair::from_seed(0);
+		let ico_addr = Address::from_public_key_bytes(ico.public_key().as_ref());
+		let receiver = Address::from_public_key_bytes(key_pair::from_seed(1).public_key().as_ref());
+		let transfer = |parent: H256, value: u64| {
+			let trx = Transaction{receiver, value: Amount::from(value), fee: Amount::ZERO, account_nonce: 1, lock_height: None, lock_time: None};
+			let mut block = generate_random_block(&parent);
+			block.content.data = vec![SignedTransaction::new(trx, &ico)];
+			block.header.merkle_root = block.compute_merkle_root();
+			solve(block)
+		};
+		let mut scanned = Blockchain::new();
+		let mut indexed = Blockchain::new();
+		let genesis_hash = scanned.tip();
+		let block_1 = transfer(genesis_hash, 10);
+		indexed.insert(&block_1).unwrap();
+		// the indexes cover the blocks inserted before they are enabled
+		indexed.enable_tx_index();
+		indexed.enable_address_index();
+		let fork_1 = generate_random_block(&genesis_hash);
+		let fork_2 = transfer(fork_1.hash(), 20);
+		scanned.insert(&block_1).unwrap();
+		for block in [&fork_1, &fork_2].iter() {
+			scanned.insert(block).unwrap();
+			indexed.insert(block).unwrap();
+		}
+
+		let (moved, kept) = (block_1.content.data[0].hash(), fork_2.content.data[0].hash());
+		for blockchain in [&scanned, &indexed].iter() {
+			assert!(blockchain.find_transaction(moved).is_err());
+			assert_eq!(blockchain.find_transaction(kept), Ok((fork_2.hash(), 0)));
+			assert_eq!(blockchain.address_transactions(ico_addr), vec![(fork_2.hash(), 0)]);
+			assert_eq!(blockchain.address_transactions(receiver), vec![(fork_2.hash(), 0)]);
+		}
+		assert_eq!(indexed.all_transactions_in_longest_chain(), vec![vec![], vec![], vec![kept]]);
+		assert_eq!(indexed.count_transactions_in_longest_chain(), 1);
+	}
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,281 @@
+use serde::{Serialize, Deserialize};
+use ring::signature::KeyPair;
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::types::address::{Address, EncodedAddress};
+use crate::types::amount::Amount;
+use crate::types::block::{Block, Header, Content, ICO_AMOUNT};
+use crate::types::hash::{H256, Hashable};
+use crate::types::key_pair;
+use crate::types::merkle::MerkleTree;
+use super::{State, MAX_BLOCK_SIZE, MEDIAN_TIME_SPAN, MAX_FUTURE_BLOCK_TIME};
+
+/// Consensus rules that may differ between chains
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsensusParams {
+	pub max_block_size: usize, // serialized size in bytes
+	pub median_time_span: usize, // blocks whose median timestamp a new block must exceed
+	pub max_future_block_time: u128, // ms a block timestamp may be ahead of the network-adjusted time
+}
+
+impl Default for ConsensusParams {
+	fn default() -> Self {
+		ConsensusParams{
+			max_block_size: MAX_BLOCK_SIZE,
+			median_time_span: MEDIAN_TIME_SPAN,
+			max_future_block_time: MAX_FUTURE_BLOCK_TIME,
+		}
+	}
+}
+
+/// Coins credited to an address in the genesis state
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Allocation {
+	#[serde(with = "as_string")]
+	pub address: EncodedAddress, // with the address version of the chain
+	pub amount: Amount,
+}
+
+/// Everything that defines a chain: its genesis block, the initial coin allocation and the consensus rules.
+/// Nodes only agree on a chain when they load the same spec, the hash of the genesis block commits to all of it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChainSpec {
+	pub name: String,
+	pub genesis_timestamp: u128, // ms since the epoch
+	#[serde(with = "as_string")]
+	pub difficulty: H256, // constant along the chain
+	pub allocations: Vec<Allocation>,
+	#[serde(default)]
+	pub address_version: u8, // version byte of encoded addresses
+	#[serde(default)]
+	pub consensus: ConsensusParams,
+}
+
+impl Default for ChainSpec {
+	fn default() -> Self {
+		Network::default().spec()
+	}
+}
+
+/// The development allocations: the ICO key (seed 0) holds every coin, the keys of seeds 1 and 2 start empty.
+/// Anyone can derive these keys, so only chains for local testing use them.
+fn development_allocations(address_version: u8) -> Vec<Allocation> {
+	let allocation = |seed: u8, amount: Amount| Allocation{
+		address: Address::from_public_key_bytes(key_pair::from_seed(seed).public_key().as_ref()).encode(address_version),
+		amount,
+	};
+	vec![allocation(0, ICO_AMOUNT), allocation(1, Amount::ZERO), allocation(2, Amount::ZERO)]
+}
+
+impl ChainSpec {
+	/// The development chain of `Blockchain::new`: the rules of mainnet with the development allocations,
+	/// for tests
+	pub fn development() -> Self {
+		let spec = Network::Mainnet.spec();
+		ChainSpec{
+			name: "development".to_string(),
+			allocations: development_allocations(spec.address_version),
+			..spec
+		}
+	}
+}
+
+/// The built-in networks. Only regtest has the development allocations, mainnet and testnet start without
+/// coins unless a chain spec allocates them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+	#[default]
+	Mainnet,
+	/// Easier proof of work, for testing with several nodes
+	Testnet,
+	/// Trivial proof of work: blocks are only mined on demand, with `/miner/mine`
+	Regtest,
+}
+
+impl Network {
+	/// Chain spec of the network
+	pub fn spec(&self) -> ChainSpec {
+		let (difficulty, address_version) = match self {
+			Network::Mainnet => (hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"), 0x00),
+			Network::Testnet => (hex!("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"), 0x6f),
+			Network::Regtest => (hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"), 0x6f),
+		};
+		let allocations = match self {
+			Network::Regtest => development_allocations(address_version),
+			_ => vec![],
+		};
+		ChainSpec{
+			name: self.to_string(),
+			genesis_timestamp: 0,
+			difficulty: difficulty.into(),
+			allocations,
+			address_version,
+			consensus: ConsensusParams::default(),
+		}
+	}
+
+	/// Default port of the P2P server
+	pub fn p2p_port(&self) -> u16 {
+		match self {
+			Network::Mainnet => 6000,
+			Network::Testnet => 16000,
+			Network::Regtest => 26000,
+		}
+	}
+
+	/// Default port of the API server
+	pub fn api_port(&self) -> u16 {
+		self.p2p_port() + 1000
+	}
+}
+
+impl std::fmt::Display for Network {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Network::Mainnet => write!(f, "mainnet"),
+			Network::Testnet => write!(f, "testnet"),
+			Network::Regtest => write!(f, "regtest"),
+		}
+	}
+}
+
+impl std::str::FromStr for Network {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Network, Self::Err> {
+		match s {
+			"mainnet" => Ok(Network::Mainnet),
+			"testnet" => Ok(Network::Testnet),
+			"regtest" => Ok(Network::Regtest),
+			_ => Err(format!("unknown network {}", s)),
+		}
+	}
+}
+
+impl ChainSpec {
+	/// Load and validate a JSON chain spec
+	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+		let path = path.as_ref();
+		let json = std::fs::read_to_string(path).map_err(|e| format!("can not read {}: {}", path.display(), e))?;
+		let spec: ChainSpec = serde_json::from_str(&json).map_err(|e| format!("can not parse {}: {}", path.display(), e))?;
+		spec.validate()?;
+		Ok(spec)
+	}
+
+	/// Check that the spec describes a usable chain
+	pub fn validate(&self) -> Result<(), String> {
+		let mut addresses: HashSet<Address> = HashSet::new();
+		let mut total = Amount::ZERO;
+		for allocation in self.allocations.iter() {
+			if let Err(e) = allocation.address.for_version(self.address_version) {
+				return Err(format!("allocation to {}: {}", allocation.address, e));
+			}
+			if !addresses.insert(allocation.address.address) {
+				return Err(format!("address {} is allocated twice", allocation.address));
+			}
+			total = total.checked_add(allocation.amount).ok_or("the allocations overflow the total supply")?;
+		}
+		if self.consensus.median_time_span == 0 {
+			return Err(String::from("the median time span must be at least one block"));
+		}
+		if self.consensus.max_block_size < Block::default().size() {
+			return Err(format!("max block size {} can not fit a block header", self.consensus.max_block_size));
+		}
+		Ok(())
+	}
+
+	/// The genesis block. It carries no transactions, so its merkle root is the one of an empty block.
+	/// Having no parent, its parent hash commits to the whole spec instead, so that chains with different
+	/// allocations or rules have different genesis hashes.
+	pub fn genesis_block(&self) -> Block {
+		let bytes = bincode::serialize(self).unwrap();
+		let commitment: H256 = ring::digest::digest(&ring::digest::SHA256, &bytes).into();
+		let content = Content{data: vec![]};
+		Block{
+			header: Header{
+				parent_hash: commitment,
+				nonce: 0,
+				extra_nonce: 0,
+				difficulty: self.difficulty,
+				timestamp: self.genesis_timestamp,
+				merkle_root: MerkleTree::new(&content.data).root(),
+			},
+			content,
+		}
+	}
+
+	/// Bytes starting every P2P frame, so that nodes on different chains do not talk to each other.
+	/// They are taken from the genesis hash, hence differ between specs.
+	pub fn magic(&self) -> [u8; 4] {
+		let hash: [u8; 32] = (&self.genesis_block().hash()).into();
+		[hash[0], hash[1], hash[2], hash[3]]
+	}
+
+	/// The state of the genesis block, made of the allocations
+	pub fn genesis_state(&self) -> State {
+		State::new(self.allocations.iter().map(|a| (a.address.address, (0, a.amount))).collect())
+	}
+}
+
+/// (De)serialize a value with its `Display` and `FromStr` implementations, so that hashes and addresses are
+/// written as strings in the spec and config files
+pub(crate) mod as_string {
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::fmt::Display;
+	use std::str::FromStr;
+
+	pub fn serialize<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(value)
+	}
+
+	pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+	where T: FromStr, T::Err: Display, D: Deserializer<'de> {
+		let s = String::deserialize(deserializer)?;
+		s.parse::<T>().map_err(serde::de::Error::custom)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn spec_defines_genesis() {
+		let spec = ChainSpec::development();
+		let json = serde_json::to_string(&spec).unwrap();
+		assert!(json.contains("\"difficulty\":\"0000ffff"));
+		assert_eq!(serde_json::from_str::<ChainSpec>(&json).unwrap(), spec);
+
+		let mut other = spec.clone();
+		other.allocations[1].amount = Amount::from(5);
+		assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+		let genesis = other.genesis_block();
+		assert_eq!(genesis.compute_merkle_root(), genesis.header.merkle_root);
+		assert_eq!(other.genesis_state().get_balance(other.allocations[1].address.address).unwrap(), (0, Amount::from(5)));
+		other.consensus.max_block_size = 2 * MAX_BLOCK_SIZE;
+		assert_ne!(other.genesis_block().hash(), spec.genesis_block().hash());
+
+		let networks = [Network::Mainnet, Network::Testnet, Network::Regtest];
+		for (i, network) in networks.iter().enumerate() {
+			assert_eq!(network.to_string().parse::<Network>(), Ok(*network));
+			// the development keys can be derived by anyone, they only hold coins on regtest
+			assert_eq!(network.spec().allocations.is_empty(), *network != Network::Regtest);
+			for other in networks[i + 1..].iter() {
+				assert_ne!(network.spec().magic(), other.spec().magic());
+				assert_ne!(network.p2p_port(), other.p2p_port());
+			}
+		}
+
+		other.allocations.push(other.allocations[0].clone());
+		assert!(other.validate().is_err());
+		other.allocations.pop();
+		other.address_version = 0x6f;
+		assert!(other.validate().is_err());
+		assert!(serde_json::from_str::<ChainSpec>(r#"{"name":"x","genesis_timestamp":0,"difficulty":"00","allocations":[]}"#).is_err());
+	}
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of hosts whose clock offset is kept
+const MAX_SAMPLES: usize = 200;
+/// Minimum number of peer samples before the local clock is adjusted
+const MIN_SAMPLES: usize = 5;
+/// Offsets larger than this (70 minutes, in ms) are ignored, the local clock is probably right
+const MAX_OFFSET: i128 = 70 * 60 * 1000;
+
+/// Current system time in milliseconds since the UNIX epoch, the unit of `Header.timestamp`
+pub fn system_time() -> u128 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+/// Network-adjusted time: the local clock corrected by the median offset of the clocks of our peers.
+/// Clones share the same samples.
+#[derive(Clone, Default)]
+pub struct NetworkTime {
+	offsets: Arc<Mutex<HashMap<IpAddr, i128>>>, // peer host -> (peer time - local time) in ms
+}
+
+impl NetworkTime {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Record the time reported by a peer. Only the first sample of each host counts,
+	/// so that a host reconnecting from other ports can not outweigh the other peers.
+	pub fn add_sample(&self, peer: IpAddr, peer_time: u128) {
+		let mut offsets = self.offsets.lock().unwrap();
+		if offsets.len() >= MAX_SAMPLES || offsets.contains_key(&peer) {
+			return;
+		}
+		offsets.insert(peer, peer_time as i128 - system_time() as i128);
+	}
+
+	/// Offset in ms applied to the local clock
+	pub fn offset(&self) -> i128 {
+		let offsets = self.offsets.lock().unwrap();
+		if offsets.len() < MIN_SAMPLES {
+			return 0;
+		}
+		let mut sorted: Vec<i128> = offsets.values().copied().collect();
+		sorted.sort_unstable();
+		let median = sorted[sorted.len() / 2];
+		if median.abs() > MAX_OFFSET {
+			return 0;
+		}
+		median
+	}
+
+	/// Network-adjusted time in ms
+	pub fn now(&self) -> u128 {
+		(system_time() as i128 + self.offset()).max(0) as u128
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(host: u8) -> IpAddr {
+		IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, host))
+	}
+
+	#[test]
+	fn median_offset_needs_enough_peers() {
+		let time = NetworkTime::new();
+		let now = system_time();
+		for host in 0..4 {
+			time.add_sample(addr(host), now + 60_000);
+		}
+		assert_eq!(time.offset(), 0);
+		time.add_sample(addr(4), now + 60_000);
+		// only the first sample of a peer counts
+		time.add_sample(addr(4), now + 600_000);
+		let offset = time.offset();
+		assert!((59_000..=60_000).contains(&offset));
+	}
+
+	#[test]
+	fn large_offsets_are_ignored() {
+		let time = NetworkTime::new();
+		let now = system_time();
+		for host in 0..5 {
+			time.add_sample(addr(host), now + 2 * MAX_OFFSET as u128);
+		}
+		assert_eq!(time.offset(), 0);
+	}
+
+	#[test]
+	fn one_sample_per_host() {
+		let time = NetworkTime::new();
+		let now = system_time();
+		for host in 0..3 {
+			time.add_sample(addr(host), now);
+		}
+		// the connections of a single host, from other ports, count once
+		for _ in 0..MAX_SAMPLES {
+			time.add_sample(addr(3), now + 60_000);
+		}
+		assert_eq!(time.offsets.lock().unwrap().len(), 4);
+		assert_eq!(time.offset(), 0);
+		for host in 4..6 {
+			time.add_sample(addr(host), now);
+		}
+		assert!(time.offset().abs() < 1_000);
+	}
+}
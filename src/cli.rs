@@ -0,0 +1,303 @@
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::fmt::Write;
+use std::fs;
+use std::io::Write as _;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::blockchain::spec::ChainSpec;
+use crate::types::address::EncodedAddress;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::key_pair::SecretKey;
+use crate::types::merkle::{self, MerkleTree};
+use crate::types::transaction::{SignedTransaction, Transaction};
+
+/// Subcommands run offline instead of starting a node
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        SubCommand::with_name("keygen")
+            .about("Generates a private key and prints its address")
+            .arg(Arg::with_name("format").long("format").takes_value(true).possible_values(&["pem", "hex", "keystore"])
+                .default_value("pem").help("Sets the format of the key: PKCS#8 PEM, hex seed or encrypted JSON keystore"))
+            .arg(Arg::with_name("out").long("out").takes_value(true).value_name("FILE")
+                .help("Writes the key to FILE instead of the standard output, FILE must not exist yet"))
+            .arg(Arg::with_name("password_file").long("password-file").takes_value(true).value_name("FILE")
+                .help("Reads the password of the keystore from FILE (default: the BITCOIN_KEY_PASSWORD variable)")),
+        SubCommand::with_name("address")
+            .about("Prints the public key and the address of a private key in PEM, hex or keystore format")
+            .arg(Arg::with_name("key").required(true).value_name("KEY_FILE"))
+            .arg(Arg::with_name("password_file").long("password-file").takes_value(true).value_name("FILE")
+                .help("Reads the password of the keystore from FILE (default: the BITCOIN_KEY_PASSWORD variable)"))
+            .arg(Arg::with_name("format").long("format").takes_value(true).possible_values(&["pem", "hex", "keystore"])
+                .help("Also prints the key converted to this format")),
+        SubCommand::with_name("sign-tx")
+            .about("Signs a transaction and prints it as hex, in the encoding of the network messages")
+            .arg(Arg::with_name("key").long("key").required(true).takes_value(true).value_name("KEY_FILE")
+                .help("Sets the key of the sender"))
+            .arg(Arg::with_name("password_file").long("password-file").takes_value(true).value_name("FILE")
+                .help("Reads the password of the keystore from FILE (default: the BITCOIN_KEY_PASSWORD variable)"))
+            .arg(Arg::with_name("receiver").long("receiver").required(true).takes_value(true).value_name("ADDRESS"))
+            .arg(Arg::with_name("value").long("value").required(true).takes_value(true).value_name("AMOUNT"))
+            .arg(Arg::with_name("fee").long("fee").takes_value(true).value_name("AMOUNT").default_value("0"))
+            .arg(Arg::with_name("nonce").long("nonce").required(true).takes_value(true)
+                .help("Sets the account nonce, one more than the nonce of the sender in the state"))
+            .arg(Arg::with_name("lock_height").long("lock-height").takes_value(true).value_name("HEIGHT"))
+            .arg(Arg::with_name("lock_time").long("lock-time").takes_value(true).value_name("MS")),
+        SubCommand::with_name("verify-tx")
+            .about("Decodes a hex transaction, checks its signatures and prints it")
+            .arg(Arg::with_name("tx").required(true).value_name("HEX_OR_FILE")),
+        SubCommand::with_name("decode-block")
+            .about("Decodes a hex block, checks its proof of work and merkle root and prints it")
+            .arg(Arg::with_name("block").required(true).value_name("HEX_OR_FILE")),
+        SubCommand::with_name("hash-header")
+            .about("Hashes the block header with the given fields")
+            .arg(Arg::with_name("parent").long("parent").required(true).takes_value(true).value_name("HASH"))
+            .arg(Arg::with_name("merkle_root").long("merkle-root").required(true).takes_value(true).value_name("HASH"))
+            .arg(Arg::with_name("difficulty").long("difficulty").required(true).takes_value(true).value_name("HASH"))
+            .arg(Arg::with_name("timestamp").long("timestamp").required(true).takes_value(true).value_name("MS"))
+            .arg(Arg::with_name("nonce").long("nonce").required(true).takes_value(true))
+            .arg(Arg::with_name("extra_nonce").long("extra-nonce").takes_value(true).default_value("0")),
+        SubCommand::with_name("merkle-proof")
+            .about("Prints and checks the merkle proof of a transaction of a hex block")
+            .arg(Arg::with_name("block").required(true).value_name("HEX_OR_FILE"))
+            .arg(Arg::with_name("tx").long("tx").required(true).takes_value(true).value_name("HASH")
+                .help("Sets the hash of the transaction")),
+    ]
+}
+
+/// Run the subcommand `name`, addresses being encoded for the chain `spec`
+pub fn run(name: &str, matches: &ArgMatches, spec: &ChainSpec) -> Result<(), String> {
+    match name {
+        "keygen" => {
+            let key = SecretKey::generate();
+            let password = read_password(matches.value_of("password_file"))?;
+            let exported = export(&key, matches.value_of("format").unwrap(), password.as_deref())?;
+            match matches.value_of("out") {
+                Some(path) => write_private_file(path, &exported).map_err(|e| format!("can not write {}: {}", path, e))?,
+                None => print!("{}", exported),
+            }
+            // the key may be on the standard output, keep it parseable
+            eprintln!("address: {}", key.address().encode(spec.address_version));
+        }
+        "address" => {
+            let password = read_password(matches.value_of("password_file"))?;
+            let key = load_key(matches.value_of("key").unwrap(), password.as_deref())?;
+            println!("public key: {}", hex::encode(key.public_key()));
+            println!("address: {}", key.address().encode(spec.address_version));
+            if let Some(format) = matches.value_of("format") {
+                print!("{}", export(&key, format, password.as_deref())?);
+            }
+        }
+        "sign-tx" => {
+            let password = read_password(matches.value_of("password_file"))?;
+            let key = load_key(matches.value_of("key").unwrap(), password.as_deref())?;
+            let receiver: EncodedAddress = arg(matches, "receiver")?;
+            let trx = Transaction{
+                receiver: receiver.for_version(spec.address_version).map_err(|e| format!("receiver: {}", e))?,
+                value: arg(matches, "value")?,
+                fee: arg(matches, "fee")?,
+                account_nonce: arg(matches, "nonce")?,
+                lock_height: optional_arg(matches, "lock_height")?,
+                lock_time: optional_arg(matches, "lock_time")?,
+            };
+            let signed = SignedTransaction::new(trx, &key.key_pair());
+            println!("{}", hex::encode(bincode::serialize(&signed).unwrap()));
+        }
+        "verify-tx" => {
+            let trx: SignedTransaction = decode(matches.value_of("tx").unwrap())?;
+            print!("{}", describe_transaction(&trx, spec));
+            if !trx.verify() {
+                return Err(String::from("invalid signatures"));
+            }
+        }
+        "decode-block" => {
+            let block: Block = decode(matches.value_of("block").unwrap())?;
+            println!("hash: {}", block.hash());
+            println!("parent: {}", block.header.parent_hash);
+            println!("difficulty: {}", block.header.difficulty);
+            println!("timestamp: {}", block.header.timestamp);
+            println!("nonce: {}", block.header.nonce);
+            println!("extra nonce: {}", block.header.extra_nonce);
+            println!("merkle root: {}", block.header.merkle_root);
+            println!("size: {}", block.size());
+            println!("proof of work valid: {}", block.hash() <= block.header.difficulty);
+            println!("merkle root valid: {}", block.compute_merkle_root() == block.header.merkle_root);
+            println!("transactions: {}", block.content.data.len());
+            for trx in block.content.data.iter() {
+                println!();
+                print!("{}", describe_transaction(trx, spec));
+            }
+        }
+        "hash-header" => {
+            let header = Header{
+                parent_hash: arg(matches, "parent")?,
+                nonce: arg(matches, "nonce")?,
+                extra_nonce: arg(matches, "extra_nonce")?,
+                difficulty: arg(matches, "difficulty")?,
+                timestamp: arg(matches, "timestamp")?,
+                merkle_root: arg(matches, "merkle_root")?,
+            };
+            println!("header: {}", hex::encode(header.to_bytes()));
+            println!("hash: {}", header.hash());
+            println!("meets difficulty: {}", header.hash() <= header.difficulty);
+        }
+        "merkle-proof" => {
+            let block: Block = decode(matches.value_of("block").unwrap())?;
+            let hash: H256 = arg(matches, "tx")?;
+            let hashes = block.get_transaction_hashes();
+            let index = hashes.iter().position(|h| *h == hash).ok_or(format!("transaction {} is not in the block", hash))?;
+            let tree = MerkleTree::new(&block.content.data);
+            let proof = tree.proof(index);
+            println!("root: {}", tree.root());
+            println!("index: {} of {}", index, hashes.len());
+            for h in proof.iter() {
+                println!("proof: {}", h);
+            }
+            let valid = merkle::verify(&block.header.merkle_root, &hash, &proof, index, hashes.len());
+            println!("valid for the block merkle root: {}", valid);
+        }
+        _ => return Err(format!("unknown subcommand {}", name)),
+    }
+    Ok(())
+}
+
+/// Parse the value of the argument `name`
+fn arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<T, String>
+where T::Err: std::fmt::Display {
+    optional_arg(matches, name)?.ok_or(format!("missing {}", name))
+}
+
+/// Parse the value of the argument `name` if it is present
+fn optional_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String>
+where T::Err: std::fmt::Display {
+    match matches.value_of(name) {
+        Some(v) => v.parse::<T>().map(Some).map_err(|e| format!("error parsing {}: {}", name, e)),
+        None => Ok(None),
+    }
+}
+
+/// Decode hex bincode given directly or in a file
+fn decode<T: serde::de::DeserializeOwned>(hex_or_file: &str) -> Result<T, String> {
+    let text = if Path::new(hex_or_file).is_file() {
+        fs::read_to_string(hex_or_file).map_err(|e| format!("can not read {}: {}", hex_or_file, e))?
+    } else {
+        hex_or_file.to_string()
+    };
+    let bytes = hex::decode(text.trim()).map_err(|e| format!("invalid hex: {}", e))?;
+    bincode::deserialize(&bytes).map_err(|e| format!("can not decode: {}", e))
+}
+
+fn describe_transaction(trx: &SignedTransaction, spec: &ChainSpec) -> String {
+    let mut s = String::new();
+    writeln!(s, "transaction: {}", trx.hash()).unwrap();
+    writeln!(s, "sender: {}", trx.sender_address().encode(spec.address_version)).unwrap();
+    writeln!(s, "receiver: {}", trx.receiver_address().encode(spec.address_version)).unwrap();
+    writeln!(s, "value: {}", trx.value()).unwrap();
+    writeln!(s, "fee: {}", trx.fee()).unwrap();
+    writeln!(s, "nonce: {}", trx.account_nonce()).unwrap();
+    writeln!(s, "signatures valid: {}", trx.verify()).unwrap();
+    s
+}
+
+/// Environment variable holding the password of keystores when no password file is given
+pub const PASSWORD_ENV: &str = "BITCOIN_KEY_PASSWORD";
+
+/// The password of keystores: the first line of `password_file`, or else the `PASSWORD_ENV` variable.
+/// It is never taken from the command line, which other users can read with `ps`.
+pub fn read_password(password_file: Option<&str>) -> Result<Option<String>, String> {
+    match password_file {
+        Some(path) => read_first_line(path).map(Some),
+        None => Ok(std::env::var(PASSWORD_ENV).ok()),
+    }
+}
+
+/// The first line of the file `path`, e.g. a password
+pub fn read_first_line(path: &str) -> Result<String, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("can not read {}: {}", path, e))?;
+    Ok(content.lines().next().unwrap_or("").to_string())
+}
+
+/// Read a private key in any supported format from `path`
+pub fn load_key(path: &str, password: Option<&str>) -> Result<SecretKey, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("can not read {}: {}", path, e))?;
+    SecretKey::load(&content, password).map_err(|e| format!("can not load key {}: {}", path, e))
+}
+
+/// Write `content` to the new file `path`, only readable by its owner on unix. An existing file is never
+/// overwritten, it may hold another key.
+fn write_private_file(path: &str, content: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+fn export(key: &SecretKey, format: &str, password: Option<&str>) -> Result<String, String> {
+    match format {
+        "hex" => Ok(format!("{}\n", key.to_hex())),
+        "keystore" => {
+            let password = password.ok_or("a keystore needs a --password-file or the BITCOIN_KEY_PASSWORD variable")?;
+            Ok(format!("{}\n", serde_json::to_string_pretty(&key.encrypt(password)).unwrap()))
+        }
+        _ => Ok(key.to_pem()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::amount::Amount;
+    use crate::types::key_pair;
+
+    fn run_args(args: &[&str]) -> Result<(), String> {
+        let matches = App::new("bitcoin").subcommands(subcommands()).get_matches_from(args);
+        let (name, sub_matches) = matches.subcommand();
+        run(name, sub_matches.unwrap(), &ChainSpec::default())
+    }
+
+    #[test]
+    fn offline_subcommands() {
+        let key = key_pair::from_seed(10);
+        let trx = Transaction{receiver: Default::default(), value: Amount::from(5), fee: Amount::ZERO, account_nonce: 1, lock_height: None, lock_time: None};
+        let trx = SignedTransaction::new(trx, &key);
+        let mut block = crate::types::block::generate_random_block(&H256::default());
+        block.content.data = vec![trx.clone(), SignedTransaction::new(Transaction::default(), &key)];
+        block.header.merkle_root = block.compute_merkle_root();
+        let block_hex = hex::encode(bincode::serialize(&block).unwrap());
+
+        assert!(run_args(&["bitcoin", "verify-tx", &hex::encode(bincode::serialize(&trx).unwrap())]).is_ok());
+        assert!(run_args(&["bitcoin", "decode-block", &block_hex]).is_ok());
+        assert!(run_args(&["bitcoin", "merkle-proof", &block_hex, "--tx", &trx.hash().to_string()]).is_ok());
+        assert!(run_args(&["bitcoin", "merkle-proof", &block_hex, "--tx", &H256::default().to_string()]).is_err());
+        assert!(run_args(&["bitcoin", "decode-block", "00"]).is_err());
+        assert!(decode::<Block>("not hex").is_err());
+    }
+
+    #[test]
+    fn password_from_file() {
+        let path = std::env::temp_dir().join(format!("bitcoin-password-{}", std::process::id()));
+        fs::write(&path, "secret\n").unwrap();
+        assert_eq!(read_password(path.to_str()), Ok(Some(String::from("secret"))));
+        fs::remove_file(&path).unwrap();
+        assert!(read_password(path.to_str()).is_err());
+    }
+
+    #[test]
+    fn keygen_writes_a_new_private_file() {
+        let path = std::env::temp_dir().join(format!("bitcoin-keygen-{}", std::process::id()));
+        let out = path.to_str().unwrap();
+        assert!(run_args(&["bitcoin", "keygen", "--format", "hex", "--out", out]).is_ok());
+        let key = fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // the first key is not overwritten
+        assert!(run_args(&["bitcoin", "keygen", "--format", "hex", "--out", out]).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), key);
+        fs::remove_file(&path).unwrap();
+    }
+}
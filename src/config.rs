@@ -0,0 +1,215 @@
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::spec::{as_string, ConsensusParams, Network};
+use crate::generator::workload::{Profile, Workload};
+use crate::miner::policy::BlockPolicy;
+use crate::types::amount::Amount;
+
+/// Settings of a node, read from a TOML file. Every field has a default, so a file only lists what it changes,
+/// and command line flags override the file. There is no storage section, the node keeps everything in memory.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: Network,
+    pub chain: Option<PathBuf>, // chain spec replacing the one of the network
+    pub p2p: P2pConfig,
+    pub api: ApiConfig,
+    pub miner: MinerConfig,
+    pub generator: GeneratorConfig,
+    pub mempool: MempoolConfig,
+    pub index: IndexConfig,
+    pub rpc: RpcConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct P2pConfig {
+    pub addr: Option<SocketAddr>, // 127.0.0.1 and the port of the network if None
+    pub connect: Vec<SocketAddr>,
+    pub workers: usize,
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        P2pConfig{addr: None, connect: vec![], workers: 4}
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub addr: Option<SocketAddr>, // 127.0.0.1 and the port of the network if None
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MinerConfig {
+    pub threads: usize,
+    pub autostart: bool, // start mining at startup, as with /miner/start?lambda=
+    pub lambda: u64,
+    pub mine_empty_blocks: bool,
+    pub min_transactions: usize,
+    pub max_transactions: Option<usize>,
+    pub max_block_size: Option<usize>, // the consensus limit if None
+    pub min_fee: Amount,
+}
+
+impl Default for MinerConfig {
+    fn default() -> Self {
+        MinerConfig{
+            threads: 1,
+            autostart: false,
+            lambda: 0,
+            mine_empty_blocks: false,
+            min_transactions: 0,
+            max_transactions: None,
+            max_block_size: None,
+            min_fee: Amount::ZERO,
+        }
+    }
+}
+
+impl MinerConfig {
+    /// The block policy of the miner, on a chain with the rules `consensus`
+    pub fn policy(&self, consensus: &ConsensusParams) -> BlockPolicy {
+        BlockPolicy{
+            mine_empty_blocks: self.mine_empty_blocks,
+            min_transactions: self.min_transactions,
+            max_transactions: self.max_transactions.unwrap_or(usize::MAX),
+            max_block_size: self.max_block_size.unwrap_or(consensus.max_block_size),
+            min_fee: self.min_fee,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorConfig {
+    pub autostart: bool, // start generating at startup, as with /tx-generator/start?theta=
+    pub theta: u64,
+    #[serde(with = "as_string")]
+    pub profile: Profile,
+    pub seed: u64,
+    pub fee: Option<Amount>, // paid by the generated transactions, the miner's min_fee if None
+    pub keys: Vec<PathBuf>, // controlled keys, the development key of the port if empty
+    pub key_password_file: Option<PathBuf>, // first line: the password of the keystores, BITCOIN_KEY_PASSWORD if None
+}
+
+impl GeneratorConfig {
+    pub fn workload(&self) -> Workload {
+        Workload{profile: self.profile.clone(), seed: self.seed}
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MempoolConfig {
+    pub max_queued: Option<usize>, // unbounded if None
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexConfig {
+    pub tx: bool, // index transactions by hash, the longest chain is scanned otherwise
+    pub address: bool, // index transactions by address
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub allow_ip: Vec<IpAddr>, // hosts other than this one allowed to call JSON-RPC methods
+    pub password_file: Option<PathBuf>, // first line: the password of HTTP basic authentication, none if None
+}
+
+impl Config {
+    /// Load a TOML config file. It is validated once the command line overrides are applied.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| format!("can not read {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("can not parse {}: {}", path.display(), e))
+    }
+
+    /// Check the settings that do not depend on the chain, the block policy is checked against the chain spec
+    pub fn validate(&self) -> Result<(), String> {
+        if self.p2p.workers == 0 {
+            return Err(String::from("p2p.workers must be at least 1"));
+        }
+        if self.miner.threads == 0 {
+            return Err(String::from("miner.threads must be at least 1"));
+        }
+        if self.mempool.max_queued == Some(0) {
+            return Err(String::from("mempool.max_queued must be at least 1"));
+        }
+        if !self.rpc.allow_ip.is_empty() && self.rpc.password_file.is_none() {
+            return Err(String::from("rpc.allow_ip needs an rpc.password_file"));
+        }
+        if self.generator.key_password_file.is_some() && self.generator.keys.is_empty() {
+            return Err(String::from("generator.key_password_file is set but there are no generator.keys"));
+        }
+        Ok(())
+    }
+
+    /// Address of the P2P server
+    pub fn p2p_addr(&self) -> SocketAddr {
+        self.p2p.addr.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], self.network.p2p_port())))
+    }
+
+    /// Address of the API server
+    pub fn api_addr(&self) -> SocketAddr {
+        self.api.addr.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], self.network.api_port())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str(r#"
+            network = "regtest"
+
+            [p2p]
+            connect = ["127.0.0.1:26001"]
+
+            [miner]
+            autostart = true
+            lambda = 1000
+            max_block_size = 10000
+
+            [generator]
+            profile = "zipf:1.5"
+            fee = 2
+
+            [mempool]
+            max_queued = 100
+
+            [index]
+            address = true
+
+            [rpc]
+            allow_ip = ["10.0.0.2"]
+            password_file = "rpc.password"
+        "#).unwrap();
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.p2p_addr(), "127.0.0.1:26000".parse().unwrap());
+        assert_eq!(config.p2p.workers, 4);
+        assert_eq!(config.miner.policy(&ConsensusParams::default()).max_block_size, 10000);
+        assert_eq!(config.generator.workload().profile, Profile::Zipf(1.5));
+        assert_eq!(config.generator.fee, Some(Amount::from(2)));
+        assert!(!config.miner.mine_empty_blocks);
+        assert_eq!(config.index, IndexConfig{tx: false, address: true});
+        assert_eq!(config.rpc.allow_ip, vec!["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert!(config.validate().is_ok());
+        let mut open_rpc = config.clone();
+        open_rpc.rpc.password_file = None;
+        assert!(open_rpc.validate().is_err());
+
+        assert!(toml::from_str::<Config>("[miner]\nthread = 2").is_err());
+        assert!(toml::from_str::<Config>("[generator]\nprofile = \"poisson\"").is_err());
+        let config: Config = toml::from_str("[miner]\nthreads = 0").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
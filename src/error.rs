@@ -0,0 +1,196 @@
+use crate::types::hash::H256;
+use std::fmt;
+
+/// Reasons for rejecting a transaction, either on its own or when applying it to a `State`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+	InvalidSignature,
+	NotFinal,
+	UnknownSender,
+	InsufficientBalance,
+	InvalidNonce { expected: u32, found: u32 },
+	NonceOverflow,
+	BalanceOverflow,
+	MempoolFull,
+}
+
+impl fmt::Display for TransactionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TransactionError::InvalidSignature => write!(f, "missing or invalid signatures for the sender account threshold"),
+			TransactionError::NotFinal => write!(f, "lock height or lock time not reached"),
+			TransactionError::UnknownSender => write!(f, "sender does not exist"),
+			TransactionError::InsufficientBalance => write!(f, "insufficient balance at sender"),
+			TransactionError::InvalidNonce{expected, found} => write!(f, "invalid account nonce, expected {} found {}", expected, found),
+			TransactionError::NonceOverflow => write!(f, "account nonce overflow"),
+			TransactionError::BalanceOverflow => write!(f, "balance overflow at receiver"),
+			TransactionError::MempoolFull => write!(f, "mempool is full"),
+		}
+	}
+}
+
+impl std::error::Error for TransactionError {}
+
+/// Reasons for rejecting a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+	InvalidProofOfWork,
+	MerkleRootMismatch,
+	BlockTooLarge(usize),
+	UnknownParent(H256),
+	DifficultyMismatch { expected: H256, found: H256 },
+	TimestampTooOld { median_time_past: u128 },
+	TimestampTooNew { max: u128 },
+	InvalidTransaction(TransactionError),
+}
+
+impl fmt::Display for BlockError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BlockError::InvalidProofOfWork => write!(f, "block hash is above its difficulty"),
+			BlockError::MerkleRootMismatch => write!(f, "merkle root does not match the block content"),
+			BlockError::BlockTooLarge(size) => write!(f, "block of {} bytes is too large", size),
+			BlockError::UnknownParent(hash) => write!(f, "parent block {} is unknown", hash),
+			BlockError::DifficultyMismatch{expected, found} => write!(f, "difficulty {} does not match the expected {}", found, expected),
+			BlockError::TimestampTooOld{median_time_past} => write!(f, "block timestamp is not after the median time past {}", median_time_past),
+			BlockError::TimestampTooNew{max} => write!(f, "block timestamp is too far in the future, max {}", max),
+			BlockError::InvalidTransaction(e) => write!(f, "invalid transaction in block: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for BlockError {}
+
+impl From<TransactionError> for BlockError {
+	fn from(e: TransactionError) -> Self {
+		BlockError::InvalidTransaction(e)
+	}
+}
+
+/// Lookups of blocks, states and transactions that are not stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+	BlockNotFound(H256),
+	HeightNotFound(u32),
+	StateNotFound(H256),
+	TransactionNotFound(H256),
+	AddressNotFound,
+}
+
+impl fmt::Display for StorageError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StorageError::BlockNotFound(hash) => write!(f, "block {} not found", hash),
+			StorageError::HeightNotFound(height) => write!(f, "no block at height {} in the longest chain", height),
+			StorageError::StateNotFound(hash) => write!(f, "state of block {} not found", hash),
+			StorageError::TransactionNotFound(hash) => write!(f, "transaction {} not found", hash),
+			StorageError::AddressNotFound => write!(f, "address does not exist in state"),
+		}
+	}
+}
+
+impl std::error::Error for StorageError {}
+
+/// Reasons for rejecting an encoded address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+	InvalidCharacter(char),
+	InvalidLength(usize),
+	InvalidChecksum,
+	WrongVersion { expected: u8, found: u8 },
+}
+
+impl fmt::Display for AddressError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AddressError::InvalidCharacter(c) => write!(f, "invalid base58 character {:?}", c),
+			AddressError::InvalidLength(len) => write!(f, "decoded address has {} bytes instead of 25", len),
+			AddressError::InvalidChecksum => write!(f, "invalid address checksum, the address is mistyped"),
+			AddressError::WrongVersion{expected, found} => write!(f, "address version {:#04x} is not the one of this network, {:#04x}", found, expected),
+		}
+	}
+}
+
+impl std::error::Error for AddressError {}
+
+/// Reasons for failing to load a private key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+	InvalidHex,
+	InvalidPem,
+	UnsupportedKey, // not an Ed25519 private key
+	InvalidKeystore(String),
+	WrongPassword,
+}
+
+impl fmt::Display for KeyError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			KeyError::InvalidHex => write!(f, "a hex seed must be 64 hex digits"),
+			KeyError::InvalidPem => write!(f, "invalid PEM, expected a PRIVATE KEY block"),
+			KeyError::UnsupportedKey => write!(f, "not an Ed25519 private key in PKCS#8"),
+			KeyError::InvalidKeystore(e) => write!(f, "invalid keystore: {}", e),
+			KeyError::WrongPassword => write!(f, "wrong password or corrupted keystore"),
+		}
+	}
+}
+
+impl std::error::Error for KeyError {}
+
+/// Errors on messages received from peers
+#[derive(Debug)]
+pub enum NetworkError {
+	Decode(bincode::Error),
+	InvalidBlock(BlockError),
+	InvalidTransaction(TransactionError),
+}
+
+impl fmt::Display for NetworkError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			NetworkError::Decode(e) => write!(f, "error decoding message: {}", e),
+			NetworkError::InvalidBlock(e) => write!(f, "invalid block: {}", e),
+			NetworkError::InvalidTransaction(e) => write!(f, "invalid transaction: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for NetworkError {}
+
+/// Ban score from which messages of a peer are ignored
+pub const BAN_THRESHOLD: u32 = 100;
+
+impl NetworkError {
+	/// How much a peer is penalised for sending this. Hosts reaching `BAN_THRESHOLD` are disconnected and banned.
+	/// Errors that an honest peer can run into, like an orphan block or a transaction
+	/// already spent on our chain, cost nothing.
+	pub fn ban_score(&self) -> u32 {
+		match self {
+			NetworkError::Decode(_) => 100,
+			NetworkError::InvalidBlock(BlockError::UnknownParent(_)) => 0,
+			// our clock may be behind, the block can become valid later
+			NetworkError::InvalidBlock(BlockError::TimestampTooNew{..}) => 0,
+			NetworkError::InvalidBlock(_) => 100,
+			NetworkError::InvalidTransaction(TransactionError::InvalidSignature) => 10,
+			NetworkError::InvalidTransaction(_) => 0,
+		}
+	}
+}
+
+impl From<bincode::Error> for NetworkError {
+	fn from(e: bincode::Error) -> Self {
+		NetworkError::Decode(e)
+	}
+}
+
+impl From<BlockError> for NetworkError {
+	fn from(e: BlockError) -> Self {
+		NetworkError::InvalidBlock(e)
+	}
+}
+
+impl From<TransactionError> for NetworkError {
+	fn from(e: TransactionError) -> Self {
+		NetworkError::InvalidTransaction(e)
+	}
+}
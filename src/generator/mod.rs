@@ -1,5 +1,7 @@
 #![allow(unused)]
 pub mod worker;
+pub mod workload;
+pub mod pending;
 
 use log::info;
 
@@ -12,13 +14,17 @@ use crate::types::hash::{H256, Hashable};
 use crate::types::transaction::{Transaction, SignedTransaction, generate_random_transaction};
 use crate::blockchain::{State, Blockchain};
 use crate::types::address::Address;
+use crate::types::amount::Amount;
 use crate::types::key_pair;
 use ring::signature::{KeyPair, Ed25519KeyPair};
 use rand::Rng;
 use std::sync::{Arc, Mutex};
+use workload::{Sampler, Workload};
+use pending::PendingView;
 
 enum ControlSignal {
     Start(u64), // the number controls the theta of interval between transaction generation
+    Workload(Workload), // switch to another workload profile or seed
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
     Exit,
 }
@@ -36,6 +42,9 @@ pub struct Context {
     generated_trx_chan: Sender<SignedTransaction>,
 	blockchain: Arc<Mutex<Blockchain>>,
 	controlled_keys: Vec<Ed25519KeyPair>,
+	sampler: Sampler, // draws the choices of the workload
+	pending: PendingView, // nonces and balances including the generated transactions not mined yet
+	fee: Amount, // paid by each generated transaction
 }
 
 #[derive(Clone)]
@@ -44,7 +53,7 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, keys: Vec<Ed25519KeyPair>) -> (Context, Handle, Receiver<SignedTransaction>) {
+pub fn new(blockchain: &Arc<Mutex<Blockchain>>, keys: Vec<Ed25519KeyPair>, workload: &Workload, fee: Amount) -> (Context, Handle, Receiver<SignedTransaction>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (generated_trx_sender, generated_trx_receiver) = unbounded();
 
@@ -54,6 +63,9 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, keys: Vec<Ed25519KeyPair>) -> (C
         generated_trx_chan: generated_trx_sender,
 		blockchain: Arc::clone(blockchain),
 		controlled_keys: keys,
+		sampler: Sampler::new(workload),
+		pending: PendingView::new(),
+		fee,
     };
 
     let handle = Handle {
@@ -70,34 +82,39 @@ fn test_new() -> (Context, Handle, Receiver<SignedTransaction>) {
 	let mut keys: Vec<Ed25519KeyPair> = vec![];
 	let key = key_pair::from_seed(0);
 	keys.push(key);
-    new(&blockchain, keys)
+    new(&blockchain, keys, &Workload::default(), Amount::ZERO)
 }
 
+// Signals sent once the generator exited are dropped: API requests may still come in while the node shuts down
 impl Handle {
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Exit);
     }
 
     pub fn start(&self, theta: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(theta))
-            .unwrap();
+        let _ = self.control_chan.send(ControlSignal::Start(theta));
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Update);
+    }
+
+    /// Generate transactions following `workload` from now on
+    pub fn set_workload(&self, workload: Workload) {
+        let _ = self.control_chan.send(ControlSignal::Workload(workload));
     }
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("generator".to_string())
             .spawn(move || {
                 self.generator_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        handle
     }
 
     fn generator_loop(&mut self) {
@@ -106,14 +123,10 @@ impl Context {
 		let mut trx: Transaction;
 		let mut key: &Ed25519KeyPair;
 		let mut signed_trx: SignedTransaction;
-		let mut num_keys: usize;
-		let mut start_idx: usize;
-		let mut i: usize;
 		let mut recv_addr: Address;
 		let mut send_addr: Address;
-		let mut tuple: (u32, u32);
-		let mut val: u32;
-		let mut init_new_key: bool;
+		let mut val: Amount;
+		let mut pending_signal: Option<ControlSignal> = None; // signal that interrupted the last wait
 
 		loop {
             // check and react to control signals
@@ -129,6 +142,10 @@ impl Context {
                             info!("Generator starting in continuous mode with theta {}", i);
                             self.operating_state = OperatingState::Run(i);
                         }
+                        ControlSignal::Workload(w) => {
+                            info!("Generator using workload {} with seed {}", w.profile, w.seed);
+                            self.sampler = Sampler::new(&w);
+                        }
                         ControlSignal::Update => {
                             // in paused state, don't need to update
                         }
@@ -138,7 +155,7 @@ impl Context {
                 OperatingState::ShutDown => {
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
+                _ => match pending_signal.take().map_or_else(|| self.control_chan.try_recv(), Ok) {
                     Ok(signal) => {
                         match signal {
                             ControlSignal::Exit => {
@@ -149,6 +166,10 @@ impl Context {
                                 info!("Generator starting in continuous mode with theta {}", i);
                                 self.operating_state = OperatingState::Run(i);
                             }
+                            ControlSignal::Workload(w) => {
+                                info!("Generator using workload {} with seed {}", w.profile, w.seed);
+                                self.sampler = Sampler::new(&w);
+                            }
                             ControlSignal::Update => {
                                 //unimplemented!()
 								continue;
@@ -170,60 +191,90 @@ impl Context {
 
 			// fetch state of tip of blockchain
 			let blockchain = self.blockchain.lock().unwrap();
-			let state: State = blockchain.get_state(blockchain.tip()).unwrap();
+			let tip = blockchain.tip();
+			let state: State = blockchain.get_state(tip).unwrap();
+			let height = blockchain.get_height(tip).unwrap();
+			// on a reorg, the generated transactions of the blocks that left the longest chain are queued again
+			let mut disconnected: Vec<SignedTransaction> = vec![];
+			if !blockchain.is_ancestor(self.pending.tip(), tip) {
+				let own: Vec<Address> = self.controlled_keys.iter().map(|key| Address::from_public_key_bytes(key.public_key().as_ref())).collect();
+				disconnected = blockchain.disconnected_blocks(self.pending.tip()).iter()
+					.flat_map(|block| block.content.data.iter())
+					.filter(|trx| own.contains(&trx.sender_address()))
+					.cloned()
+					.collect();
+			}
 			drop(blockchain);
-
-
-			num_keys = self.controlled_keys.len();
-			start_idx = rand::thread_rng().gen_range(0..num_keys);
-			i = 0;
-			init_new_key = false;
-			let mut new_key: Ed25519KeyPair = key_pair::random();
-			while i<num_keys {
-				key = &self.controlled_keys[ (start_idx+i)%num_keys ];
+			self.pending.sync(tip, height, &state, &disconnected);
+
+
+			// keys that can send: funded, and with a nonce left, counting the transactions not mined yet
+			let accounts: Vec<Address> = state.addresses();
+			let pending = &self.pending;
+			let fee = self.fee;
+			let senders: Vec<(usize, u32, Amount)> = self.controlled_keys.iter().enumerate().filter_map(|(i, key)| {
+				// when a new key is added in a previously generated trx but that trx is not yet mined, the state does not contain its corresponding address
+				let (next_nonce, bal) = pending.account(Address::from_public_key_bytes(key.public_key().as_ref()), &state)?;
+				let bal = bal.checked_sub(fee)?; // what is left to send once the fee is paid
+				if bal.is_zero() { None } else { Some((i, next_nonce, bal)) }
+			}).collect();
+			let mut sent = false;
+			if !senders.is_empty() {
+				let (idx, next_nonce, bal) = senders[ self.sampler.pick_sender(senders.len()) ];
+				key = &self.controlled_keys[idx];
 				send_addr = Address::from_public_key_bytes(key.public_key().as_ref());
-				let (nonce, bal) = match state.get_balance(send_addr) {
-					Ok(tuple) => tuple,
-					Err(e) => {i=i+1; continue;}, // when a new key is added in a previously generated trx but that trx is not yet mined, the state does not contain its corresponding address
+				recv_addr = self.sampler.pick_receiver(&accounts);
+				let mut new_key: Option<Ed25519KeyPair> = None;
+				if recv_addr == send_addr { // to introduce new key with a small probability
+					let k = self.sampler.new_key();
+					recv_addr = Address::from_public_key_bytes(k.public_key().as_ref());
+					new_key = Some(k);
+				}
+				val = self.sampler.pick_value(bal);
+				trx = Transaction{
+					receiver: recv_addr,
+					value: val,
+					fee: self.fee,
+					account_nonce: next_nonce,
+					lock_height: None,
+					lock_time: None,
 				};
-				if bal > 0 {
-					recv_addr = *state.get_random_addr().unwrap();
-					if recv_addr == send_addr { // to introduce new key with a small probability
-						new_key = key_pair::random();
-						recv_addr = Address::from_public_key_bytes(new_key.public_key().as_ref());
-						//self.controlled_keys.push(new_key);
-						init_new_key = true;
-					}
-					val = rand::thread_rng().gen_range(1..bal);
-					trx = Transaction{
-						receiver: recv_addr,
-						value: val,
-						account_nonce: nonce+1
-					};
-					signed_trx = SignedTransaction::new(trx, key);
-					//println!("generated strx, hash:{}, send:{},bal:{},recv:{},nonce:{},val:{}", signed_trx.hash(), Address::from_public_key_bytes(key.public_key().as_ref()), bal, recv_addr, nonce+1, val);
-
-					if init_new_key {
-						self.controlled_keys.push(new_key);
-					}
-					// TODO for student: if transaction generation is finished, you can have something like: self.generated_trx_chan.send(block.clone()).expect("Send finished block error");
-					self.generated_trx_chan.send(signed_trx.clone()).expect("Send finished signed_trx error");
-
-					break;
+				signed_trx = SignedTransaction::new(trx, key);
+				//println!("generated strx, hash:{}, send:{},bal:{},recv:{},nonce:{},val:{}", signed_trx.hash(), Address::from_public_key_bytes(key.public_key().as_ref()), bal, recv_addr, nonce+1, val);
+
+				if let Some(k) = new_key {
+					self.controlled_keys.push(k);
 				}
-				i=i+1;
+				// TODO for student: if transaction generation is finished, you can have something like: self.generated_trx_chan.send(block.clone()).expect("Send finished block error");
+				self.generated_trx_chan.send(signed_trx.clone()).expect("Send finished signed_trx error");
+				self.pending.add(&signed_trx, height);
+				sent = true;
 			}
 
 			//if num_keys>0 && i==num_keys {
 			//	println!("all senders are zero-balance.");
 			//}
 
+			// bursts are sent back to back, and followed by a pause
+			let mut wait = true;
+			let mut duration = time::Duration::from_micros(0);
+			if sent {
+				match self.sampler.pause() {
+					Some(pause) => duration += pause,
+					None => wait = !self.sampler.in_burst(),
+				}
+			}
             if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros((i as f64 * 0.5 * 1e4) as u64);
-                    thread::sleep(interval);
+                if i != 0 && wait {
+                    duration += time::Duration::from_micros((i as f64 * 0.5 * 1e4) as u64);
                 }
             }
+			// a signal ends the wait early, so that a long theta does not delay a shutdown
+			if duration > time::Duration::from_micros(0) {
+				if let Ok(signal) = self.control_chan.recv_timeout(duration) {
+					pending_signal = Some(signal);
+				}
+			}
         }
     }
 }
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use crate::blockchain::State;
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::hash::H256;
+use crate::types::transaction::{SignedTransaction, MAX_NONCE_GAP};
+
+/// Blocks after which a pending transaction that is still not mined is given up, with the ones after it
+pub const PENDING_EXPIRY_BLOCKS: u32 = 20;
+
+/// The accounts of the controlled keys as the generator sees them: the state of the tip of the blockchain
+/// plus the transactions it generated that are not mined yet.
+#[derive(Default)]
+pub struct PendingView {
+	tip: H256, // block the view is in sync with
+	pending: HashMap<Address, Vec<(SignedTransaction, u32)>>, // sender -> (transaction, tip height when sent), by nonce
+}
+
+impl PendingView {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Block the view was last synced with
+	pub fn tip(&self) -> H256 {
+		self.tip
+	}
+
+	/// Sync the view with the tip `tip` at `height`, with state `state`. Mined transactions are dropped.
+	/// On a reorg, `disconnected` holds the generated transactions of the blocks that left the longest chain:
+	/// they wait in the mempool again, so they are pending unless the new chain already used their nonce.
+	pub fn sync(&mut self, tip: H256, height: u32, state: &State, disconnected: &[SignedTransaction]) {
+		for trx in disconnected {
+			let trxs = self.pending.entry(trx.sender_address()).or_default();
+			if trxs.iter().all(|(pending, _)| pending.account_nonce() != trx.account_nonce()) {
+				trxs.push((trx.clone(), height));
+			}
+		}
+		for trxs in self.pending.values_mut() {
+			trxs.sort_by_key(|(trx, _)| trx.account_nonce());
+		}
+		self.tip = tip;
+		self.pending.retain(|addr, trxs| {
+			let nonce = state.get_balance(*addr).map(|(nonce, _)| nonce).unwrap_or(0);
+			trxs.retain(|(trx, _)| trx.account_nonce() > nonce);
+			// a transaction that is not mined in time is probably lost, the next ones can not be mined without it
+			let expired = matches!(trxs.first(), Some((_, sent_at)) if height >= sent_at + PENDING_EXPIRY_BLOCKS);
+			!trxs.is_empty() && !expired
+		});
+	}
+
+	/// Next nonce and spendable balance of `addr`, or `None` if it can not send now
+	pub fn account(&self, addr: Address, state: &State) -> Option<(u32, Amount)> {
+		let (state_nonce, mut balance) = state.get_balance(addr).ok()?;
+		let mut nonce = state_nonce;
+		if let Some(trxs) = self.pending.get(&addr) {
+			for (trx, _) in trxs {
+				nonce = trx.account_nonce();
+				balance = balance.checked_sub(trx.value().checked_add(trx.fee())?)?;
+			}
+		}
+		// mempools refuse nonces further ahead, the account waits for its transactions to be mined
+		if nonce.saturating_sub(state_nonce) >= MAX_NONCE_GAP {
+			return None;
+		}
+		Some((nonce.checked_add(1)?, balance))
+	}
+
+	/// Record a transaction sent when the tip was at `height`
+	pub fn add(&mut self, trx: &SignedTransaction, height: u32) {
+		self.pending.entry(trx.sender_address()).or_default().push((trx.clone(), height));
+	}
+
+	/// Number of transactions not mined yet
+	pub fn count(&self) -> usize {
+		self.pending.values().map(|trxs| trxs.len()).sum()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::key_pair;
+	use crate::types::transaction::Transaction;
+	use ring::signature::KeyPair;
+
+	fn send(key: &ring::signature::Ed25519KeyPair, value: u64, nonce: u32) -> SignedTransaction {
+		let receiver = Address::from_public_key_bytes(key_pair::from_seed(11).public_key().as_ref());
+		let trx = Transaction{receiver, value: Amount::from(value), fee: Amount::ZERO, account_nonce: nonce, lock_height: None, lock_time: None};
+		SignedTransaction::new(trx, key)
+	}
+
+	#[test]
+	fn pending_nonces_and_reorg() {
+		let key = key_pair::from_seed(10);
+		let addr = Address::from_public_key_bytes(key.public_key().as_ref());
+		let mut state = State::new(vec![(addr, (0, Amount::from(100)))]);
+		let mut view = PendingView::new();
+		view.sync([1u8; 32].into(), 0, &state, &[]);
+
+		assert_eq!(view.account(addr, &state), Some((1, Amount::from(100))));
+		let first = send(&key, 30, 1);
+		view.add(&first, 0);
+		assert_eq!(view.account(addr, &state), Some((2, Amount::from(70))));
+		view.add(&send(&key, 20, 2), 0);
+		assert_eq!(view.account(addr, &state), Some((3, Amount::from(50))));
+
+		// the first one is mined
+		let state_1 = state.update(&vec![first.clone()], 1, 0).unwrap();
+		view.sync([2u8; 32].into(), 1, &state_1, &[]);
+		assert_eq!(view.count(), 1);
+		assert_eq!(view.account(addr, &state_1), Some((3, Amount::from(50))));
+
+		// back to a chain where nothing is mined: the first one is pending again, the second one stays
+		view.sync([3u8; 32].into(), 2, &state, &[first]);
+		assert_eq!(view.count(), 2);
+		assert_eq!(view.account(addr, &state), Some((3, Amount::from(50))));
+
+		// transactions that are never mined expire
+		view.sync([4u8; 32].into(), 2 + PENDING_EXPIRY_BLOCKS, &state, &[]);
+		assert_eq!(view.count(), 0);
+	}
+}
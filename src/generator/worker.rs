@@ -1,10 +1,11 @@
 #![allow(unused)]
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use log::{debug, error, info};
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use crate::network::message::Message;
+use crate::blockchain::Blockchain;
 use crate::types::hash::Hashable;
 use crate::types::transaction::{SignedTransaction, TransactionMempool};
 
@@ -12,6 +13,7 @@ use crate::types::transaction::{SignedTransaction, TransactionMempool};
 pub struct Worker {
     server: ServerHandle,
     generated_trx_chan: Receiver<SignedTransaction>,
+	blockchain: Arc<Mutex<Blockchain>>, // generated transactions are checked against its tip state
 	mempool: Arc<Mutex<TransactionMempool>>,
 }
 
@@ -19,31 +21,39 @@ impl Worker {
     pub fn new(
         server: &ServerHandle,
         generated_trx_chan: Receiver<SignedTransaction>,
+		blockchain: &Arc<Mutex<Blockchain>>,
 		mempool: &Arc<Mutex<TransactionMempool>>,
     ) -> Self {
         Self {
             server: server.clone(),
             generated_trx_chan,
+			blockchain: Arc::clone(blockchain),
 			mempool: Arc::clone(mempool),
         }
     }
 
-    pub fn start(self) {
-        thread::Builder::new()
+    pub fn start(self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("generator-worker".to_string())
             .spawn(move || {
                 self.worker_loop();
             })
             .unwrap();
         info!("Generator initialized into paused mode");
+        handle
     }
 
     fn worker_loop(&self) {
-        loop {
-            let _signed_trx = self.generated_trx_chan.recv().expect("Receive finished signed_trx error");
+        // the channel disconnects when the generator exits
+        while let Ok(_signed_trx) = self.generated_trx_chan.recv() {
             // TODO for student: insert this finished signed_trx to mempool, and broadcast this signed_trx hash
+			let blockchain = self.blockchain.lock().unwrap();
+			let tip_state = blockchain.get_state(blockchain.tip()).unwrap();
 			let mut mempool = self.mempool.lock().unwrap();
-			mempool.insert(&_signed_trx, true);
+			if let Err(e) = mempool.insert(&_signed_trx, Some(&tip_state)) {
+				error!("generated an invalid transaction {}: {}", _signed_trx.hash(), e);
+				continue;
+			}
 			// broadcast the signed_trx hash
 			self.server.broadcast(Message::NewTransactionHashes(vec![_signed_trx.hash()]));
         }
@@ -0,0 +1,222 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::time;
+
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+
+/// How the generator picks senders, receivers, values and arrival times
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Profile {
+	/// Any funded key pays a random fraction of its balance to any account
+	#[default]
+	Uniform,
+	/// Senders and receivers follow a Zipf distribution of the given exponent: a few hot accounts
+	/// take most of the traffic
+	Zipf(f64),
+	/// Many-to-one payments: funded keys make small payments to one of this many merchant accounts
+	Merchant(usize),
+	/// Uniform transactions arriving in bursts of `burst` back to back, with a pause of `pause_ms` in between
+	Bursty { burst: usize, pause_ms: u64 },
+}
+
+impl std::fmt::Display for Profile {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Profile::Uniform => write!(f, "uniform"),
+			Profile::Zipf(exponent) => write!(f, "zipf:{}", exponent),
+			Profile::Merchant(merchants) => write!(f, "merchant:{}", merchants),
+			Profile::Bursty{burst, pause_ms} => write!(f, "bursty:{}:{}", burst, pause_ms),
+		}
+	}
+}
+
+impl std::str::FromStr for Profile {
+	type Err = String;
+
+	/// Parse `uniform`, `zipf[:EXPONENT]`, `merchant[:MERCHANTS]` or `bursty[:BURST[:PAUSE_MS]]`
+	fn from_str(s: &str) -> Result<Profile, Self::Err> {
+		let mut parts = s.split(':');
+		let name = parts.next().unwrap_or("");
+		let params: Vec<&str> = parts.collect();
+		let param = |i: usize, default: &str| -> String { params.get(i).copied().unwrap_or(default).to_string() };
+		let error = |e: &dyn std::fmt::Display| format!("invalid parameter for profile {}: {}", name, e);
+		let max_params = match name {
+			"uniform" => 0,
+			"zipf" | "merchant" => 1,
+			"bursty" => 2,
+			_ => return Err(format!("unknown workload profile {}", name)),
+		};
+		if params.len() > max_params {
+			return Err(format!("too many parameters for profile {}", name));
+		}
+		let profile = match name {
+			"zipf" => {
+				let exponent = param(0, "1.0").parse::<f64>().map_err(|e| error(&e))?;
+				if exponent.is_nan() || exponent <= 0.0 {
+					return Err(error(&"the exponent must be positive"));
+				}
+				Profile::Zipf(exponent)
+			}
+			"merchant" => {
+				let merchants = param(0, "1").parse::<usize>().map_err(|e| error(&e))?;
+				if merchants == 0 {
+					return Err(error(&"at least one merchant is needed"));
+				}
+				Profile::Merchant(merchants)
+			}
+			"bursty" => Profile::Bursty{
+				burst: param(0, "10").parse::<usize>().map_err(|e| error(&e))?.max(1),
+				pause_ms: param(1, "1000").parse::<u64>().map_err(|e| error(&e))?,
+			},
+			_ => Profile::Uniform,
+		};
+		Ok(profile)
+	}
+}
+
+/// A workload profile with the seed of its random number generator, so that runs can be reproduced
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Workload {
+	pub profile: Profile,
+	pub seed: u64,
+}
+
+/// Draws the choices of a workload
+pub struct Sampler {
+	profile: Profile,
+	rng: StdRng,
+	merchants: Vec<Address>,
+	sent_in_burst: usize,
+}
+
+impl Sampler {
+	pub fn new(workload: &Workload) -> Self {
+		let mut rng = StdRng::seed_from_u64(workload.seed);
+		let merchants = match workload.profile {
+			Profile::Merchant(n) => (0..n).map(|_| Address::from_public_key_bytes(Self::new_key_with(&mut rng).public_key().as_ref())).collect(),
+			_ => vec![],
+		};
+		Sampler{profile: workload.profile.clone(), rng, merchants, sent_in_burst: 0}
+	}
+
+	fn new_key_with(rng: &mut StdRng) -> Ed25519KeyPair {
+		let seed: [u8; 32] = rng.gen();
+		Ed25519KeyPair::from_seed_unchecked(&seed).unwrap()
+	}
+
+	/// A new key, derived from the seed of the workload
+	pub fn new_key(&mut self) -> Ed25519KeyPair {
+		Self::new_key_with(&mut self.rng)
+	}
+
+	/// Index in `0..n` following a Zipf distribution, index 0 being the hottest
+	fn zipf(&mut self, n: usize, exponent: f64) -> usize {
+		let weights: Vec<f64> = (1..=n).map(|k| 1.0 / (k as f64).powf(exponent)).collect();
+		let mut x = self.rng.gen::<f64>() * weights.iter().sum::<f64>();
+		for (i, w) in weights.iter().enumerate() {
+			if x < *w {
+				return i;
+			}
+			x -= w;
+		}
+		n - 1
+	}
+
+	/// Pick a sender among `n` candidates, in the order of the controlled keys
+	pub fn pick_sender(&mut self, n: usize) -> usize {
+		match self.profile {
+			Profile::Zipf(exponent) => self.zipf(n, exponent),
+			_ => self.rng.gen_range(0..n),
+		}
+	}
+
+	/// Pick a receiver among the accounts of the ledger, sorted so that the choice only depends on the seed
+	pub fn pick_receiver(&mut self, accounts: &[Address]) -> Address {
+		match self.profile {
+			Profile::Merchant(_) => {
+				let i = self.rng.gen_range(0..self.merchants.len());
+				self.merchants[i]
+			}
+			Profile::Zipf(exponent) => accounts[self.zipf(accounts.len(), exponent)],
+			_ => accounts[self.rng.gen_range(0..accounts.len())],
+		}
+	}
+
+	/// Pick the value sent out of a non-zero spendable `balance`. At most a tenth of it is sent, so that
+	/// a key can keep sending while its previous transactions are not mined yet.
+	pub fn pick_value(&mut self, balance: Amount) -> Amount {
+		let max = match self.profile {
+			Profile::Merchant(_) => (balance.as_u64() / 100).max(1), // small payments
+			_ => (balance.as_u64() / 10).max(1),
+		};
+		Amount::from(self.rng.gen_range(1..=max))
+	}
+
+	/// Pause to take after sending a transaction, on top of the interval set by theta
+	pub fn pause(&mut self) -> Option<time::Duration> {
+		if let Profile::Bursty{burst, pause_ms} = self.profile {
+			self.sent_in_burst += 1;
+			if self.sent_in_burst >= burst {
+				self.sent_in_burst = 0;
+				return Some(time::Duration::from_millis(pause_ms));
+			}
+		}
+		None
+	}
+
+	/// Whether transactions of the current burst are sent without waiting for theta
+	pub fn in_burst(&self) -> bool {
+		matches!(self.profile, Profile::Bursty{..}) && self.sent_in_burst != 0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_profiles() {
+		assert_eq!("uniform".parse::<Profile>(), Ok(Profile::Uniform));
+		assert_eq!("zipf:1.5".parse::<Profile>(), Ok(Profile::Zipf(1.5)));
+		assert_eq!("merchant".parse::<Profile>(), Ok(Profile::Merchant(1)));
+		assert_eq!("bursty:5:200".parse::<Profile>(), Ok(Profile::Bursty{burst: 5, pause_ms: 200}));
+		assert!("zipf:-1".parse::<Profile>().is_err());
+		assert!("uniform:1".parse::<Profile>().is_err());
+		assert!("poisson".parse::<Profile>().is_err());
+		let profile = Profile::Bursty{burst: 5, pause_ms: 200};
+		assert_eq!(profile.to_string().parse::<Profile>(), Ok(profile));
+	}
+
+	#[test]
+	fn same_seed_same_choices() {
+		let workload = Workload{profile: Profile::Zipf(1.2), seed: 42};
+		let draw = |sampler: &mut Sampler| -> Vec<usize> { (0..200).map(|_| sampler.pick_sender(10)).collect() };
+		let choices = draw(&mut Sampler::new(&workload));
+		assert_eq!(choices, draw(&mut Sampler::new(&workload)));
+		// the hottest account is picked the most
+		let hottest = choices.iter().filter(|i| **i == 0).count();
+		assert!((1..10).all(|k| choices.iter().filter(|i| **i == k).count() <= hottest));
+	}
+
+	#[test]
+	fn merchants_receive_everything() {
+		let mut sampler = Sampler::new(&Workload{profile: Profile::Merchant(2), seed: 1});
+		let accounts = vec![Address::default()];
+		let receivers: std::collections::HashSet<Address> = (0..20).map(|_| sampler.pick_receiver(&accounts)).collect();
+		assert!(receivers.len() <= 2);
+		assert!(!receivers.contains(&Address::default()));
+		assert_eq!(sampler.pick_value(Amount::from(50)), Amount::from(1));
+	}
+
+	#[test]
+	fn bursts_pause() {
+		let mut sampler = Sampler::new(&Workload{profile: Profile::Bursty{burst: 3, pause_ms: 10}, seed: 1});
+		assert_eq!(sampler.pause(), None);
+		assert!(sampler.in_burst());
+		assert_eq!(sampler.pause(), None);
+		assert_eq!(sampler.pause(), Some(time::Duration::from_millis(10)));
+		assert!(!sampler.in_burst());
+	}
+}
@@ -3,131 +3,249 @@
 extern crate hex_literal;
 
 pub mod api;
+pub mod cli;
 pub mod blockchain;
+pub mod config;
+pub mod error;
 pub mod types;
 pub mod miner;
 pub mod network;
 pub mod generator;
 
 use blockchain::Blockchain;
+use blockchain::spec::{ChainSpec, Network};
+use config::Config;
 use types::transaction::TransactionMempool;
-use ring::signature::Ed25519KeyPair;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use types::address::Address;
 use types::key_pair;
 use clap::clap_app;
 use smol::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
 use std::net;
+use std::path::PathBuf;
 use std::process;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
+/// Parse the value of an optional command line argument, exiting on invalid values
+fn parse_arg<T: FromStr>(matches: &clap::ArgMatches, name: &str) -> Option<T>
+where T::Err: std::fmt::Display {
+    matches.value_of(name).map(|v| {
+        v.parse::<T>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", name, e);
+            process::exit(1);
+        })
+    })
+}
+
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
      (version: "0.1")
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg config: --config +global [FILE] "Sets the TOML config file, command line flags override its settings")
+     (@arg network: --network +global [NETWORK] "Sets the network: mainnet, testnet or regtest (default: mainnet)")
+     (@arg peer_addr: --p2p [ADDR] "Sets the IP address and the port of the P2P server (default: 127.0.0.1 and the port of the network)")
+     (@arg api_addr: --api [ADDR] "Sets the IP address and the port of the API server (default: 127.0.0.1 and the port of the network)")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
-     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg chain: --chain +global [FILE] "Sets the JSON chain spec defining the genesis block, the initial allocations and the consensus rules (default: the spec of the network)")
+     (@arg key: --key ... [KEY_FILE] "Controls the key in KEY_FILE (PEM, hex seed or keystore) instead of the development key of the port")
+     (@arg rpc_allow_ip: --rpcallowip ... [IP] "Allows the host IP to call JSON-RPC methods, which needs --rpc-password-file")
+     (@arg rpc_password_file: --("rpc-password-file") [FILE] "Requires the password in FILE for JSON-RPC calls, with HTTP basic authentication")
+     (@arg key_password_file: --("key-password-file") [FILE] "Reads the password of the keystores given with --key from FILE (default: the BITCOIN_KEY_PASSWORD variable)")
+     (@arg p2p_workers: --("p2p-workers") [INT] "Sets the number of worker threads for P2P server (default: 4)")
+     (@arg miner_threads: --("miner-threads") [INT] "Sets the number of proof-of-work threads of the miner (default: 1)")
+     (@arg start_miner: --("start-miner") "Starts mining at startup, as /miner/start does")
+     (@arg lambda: --lambda [LAMBDA] "Sets the wait between mined blocks in microseconds when mining starts at startup")
+     (@arg empty_blocks: --("empty-blocks") "Mines blocks without transactions when there are none to include")
+     (@arg block_min_tx: --("block-min-tx") [INT] "Sets the number of transactions to wait for before mining a block")
+     (@arg block_max_tx: --("block-max-tx") [INT] "Sets the maximum number of transactions in a mined block")
+     (@arg block_max_size: --("block-max-size") [BYTES] "Sets the maximum serialized size of a mined block")
+     (@arg min_fee: --("min-fee") [AMOUNT] "Sets the minimum fee of transactions included in mined blocks")
+     (@arg tx_profile: --("tx-profile") [PROFILE] "Sets the workload of the transaction generator: uniform, zipf[:EXPONENT], merchant[:MERCHANTS] or bursty[:BURST[:PAUSE_MS]]")
+     (@arg tx_seed: --("tx-seed") [INT] "Sets the seed of the transaction generator")
+     (@arg tx_fee: --("tx-fee") [AMOUNT] "Sets the fee of generated transactions (default: the minimum fee of the miner)")
+     (@arg start_generator: --("start-generator") "Starts the transaction generator at startup, as /tx-generator/start does")
+     (@arg theta: --theta [THETA] "Sets the wait between generated transactions when the generator starts at startup")
+     (@arg mempool_max: --("mempool-max") [INT] "Sets the number of transactions waiting to be mined from which the mempool refuses new ones")
+     (@arg txindex: --txindex "Indexes the transactions of the longest chain by hash")
+     (@arg addrindex: --addrindex "Indexes the transactions of the longest chain by address")
     )
+    .subcommands(cli::subcommands())
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(blockchain));
-	let mempool = TransactionMempool::new();
-	let mempool = Arc::new(Mutex::new(mempool));
-    // parse p2p server address
-    let p2p_addr = matches
-        .value_of("peer_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P server address: {}", e);
+
+    // load the config file, and override it with the command line flags
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::from_file(path).unwrap_or_else(|e| {
+            error!("Error loading config: {}", e);
             process::exit(1);
-        });
+        }),
+        None => Config::default(),
+    };
+    config.network = parse_arg(&matches, "network").unwrap_or(config.network);
+    config.chain = matches.value_of("chain").map(PathBuf::from).or(config.chain);
+    config.p2p.addr = parse_arg(&matches, "peer_addr").or(config.p2p.addr);
+    config.api.addr = parse_arg(&matches, "api_addr").or(config.api.addr);
+    if let Some(peers) = matches.values_of("known_peer") {
+        config.p2p.connect = peers.map(|peer| peer.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing peer address {}: {}", peer, e);
+            process::exit(1);
+        })).collect();
+    }
+    if let Some(ips) = matches.values_of("rpc_allow_ip") {
+        config.rpc.allow_ip = ips.map(|ip| ip.parse::<net::IpAddr>().unwrap_or_else(|e| {
+            error!("Error parsing RPC allowed IP {}: {}", ip, e);
+            process::exit(1);
+        })).collect();
+    }
+    config.rpc.password_file = matches.value_of("rpc_password_file").map(PathBuf::from).or(config.rpc.password_file);
+    config.p2p.workers = parse_arg(&matches, "p2p_workers").unwrap_or(config.p2p.workers);
+    config.miner.threads = parse_arg(&matches, "miner_threads").unwrap_or(config.miner.threads);
+    config.miner.autostart |= matches.is_present("start_miner");
+    config.miner.lambda = parse_arg(&matches, "lambda").unwrap_or(config.miner.lambda);
+    config.miner.mine_empty_blocks |= matches.is_present("empty_blocks");
+    config.miner.min_transactions = parse_arg(&matches, "block_min_tx").unwrap_or(config.miner.min_transactions);
+    config.miner.max_transactions = parse_arg(&matches, "block_max_tx").or(config.miner.max_transactions);
+    config.miner.max_block_size = parse_arg(&matches, "block_max_size").or(config.miner.max_block_size);
+    config.miner.min_fee = parse_arg(&matches, "min_fee").unwrap_or(config.miner.min_fee);
+    config.generator.autostart |= matches.is_present("start_generator");
+    config.generator.theta = parse_arg(&matches, "theta").unwrap_or(config.generator.theta);
+    config.generator.profile = parse_arg(&matches, "tx_profile").unwrap_or(config.generator.profile);
+    config.generator.seed = parse_arg(&matches, "tx_seed").unwrap_or(config.generator.seed);
+    config.generator.fee = parse_arg(&matches, "tx_fee").or(config.generator.fee);
+    if let Some(paths) = matches.values_of("key") {
+        config.generator.keys = paths.map(PathBuf::from).collect();
+    }
+    config.generator.key_password_file = matches.value_of("key_password_file").map(PathBuf::from).or(config.generator.key_password_file);
+    config.mempool.max_queued = parse_arg(&matches, "mempool_max").or(config.mempool.max_queued);
+    config.index.tx |= matches.is_present("txindex");
+    config.index.address |= matches.is_present("addrindex");
+    if let Err(e) = config.validate() {
+        error!("Invalid config: {}", e);
+        process::exit(1);
+    }
 
-    // parse api server address
-    let api_addr = matches
-        .value_of("api_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing API server address: {}", e);
+    let spec = match &config.chain {
+        Some(path) => ChainSpec::from_file(path).unwrap_or_else(|e| {
+            error!("Error loading chain spec: {}", e);
             process::exit(1);
-        });
+        }),
+        None => config.network.spec(),
+    };
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        if let Err(e) = cli::run(name, sub_matches, &spec) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let policy = config.miner.policy(&spec.consensus);
+    if let Err(e) = policy.validate(&spec.consensus) {
+        error!("Invalid block policy: {}", e);
+        process::exit(1);
+    }
+    let mut blockchain = Blockchain::with_spec(&spec);
+    if config.index.tx {
+        blockchain.enable_tx_index();
+    }
+    if config.index.address {
+        blockchain.enable_address_index();
+    }
+    info!("Chain {} with genesis block {}", spec.name, blockchain.tip());
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = match config.mempool.max_queued {
+        Some(max) => TransactionMempool::with_max_queued(max),
+        None => TransactionMempool::new(),
+    };
+    let mempool = Arc::new(Mutex::new(mempool));
+    let p2p_addr = config.p2p_addr();
+    let api_addr = config.api_addr();
+
+    // shut down on SIGINT, SIGTERM or /admin/shutdown
+    let (shutdown_tx, shutdown_rx) = crossbeam::channel::bounded(1);
+    {
+        let shutdown_tx = shutdown_tx.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            let _ = shutdown_tx.try_send(());
+        }) {
+            error!("Error setting the signal handler: {}", e);
+            process::exit(1);
+        }
+    }
 
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx, spec.magic()).unwrap();
     server_ctx.start().unwrap();
 
-    // start the worker
-    let p2p_workers = matches
-        .value_of("p2p_workers")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P workers: {}", e);
+    // load the controlled keys. Without keys, the local regtest nodes on the first three ports of the network
+    // control the development keys of seeds 0, 1 and 2, which anyone can derive
+    let mut keys: Vec<Ed25519KeyPair> = vec![];
+    if !config.generator.keys.is_empty() {
+        let password_file = config.generator.key_password_file.as_ref().map(|path| path.to_string_lossy().into_owned());
+        let password = cli::read_password(password_file.as_deref()).unwrap_or_else(|e| {
+            error!("Error reading the key password: {}", e);
             process::exit(1);
         });
+        for path in config.generator.keys.iter() {
+            match cli::load_key(&path.to_string_lossy(), password.as_deref()) {
+                Ok(key) => keys.push(key.key_pair()),
+                Err(e) => {
+                    error!("Error loading key: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    } else {
+        match p2p_addr.port().checked_sub(config.network.p2p_port()) {
+            Some(seed) if config.network == Network::Regtest && p2p_addr.ip().is_loopback() && seed < 3 => keys.push(key_pair::from_seed(seed as u8)),
+            _ => warn!("No controlled keys assigned, use --key outside of local regtest nodes"),
+        }
+    }
+    for key in keys.iter() {
+        let address = Address::from_public_key_bytes(key.public_key().as_ref());
+        info!("Controlling the key of address {}", address.encode(spec.address_version));
+    }
+    // start the generator
+    let (generator_ctx, generator, gn_finished_block_chan) = generator::new(&blockchain, keys, &config.generator.workload(), config.generator.fee.unwrap_or(config.miner.min_fee));
+    let generator_worker_ctx = generator::worker::Worker::new(&server, gn_finished_block_chan, &blockchain, &mempool);
+    let generator_threads = vec![generator_ctx.start(), generator_worker_ctx.start()];
+
+    // start the miner
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, config.miner.threads, policy);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain, &miner);
+    let miner_threads = vec![miner_ctx.start(), miner_worker_ctx.start()];
+
+    // start the worker, it notifies the miner about new tips
+    let msg_chan = msg_rx.clone();
     let worker_ctx = network::worker::Worker::new(
-        p2p_workers,
+        config.p2p.workers,
         msg_rx,
         &server,
         &blockchain,
-		&mempool,
+        &mempool,
+        &miner,
     );
-    worker_ctx.start();
-
-	// distribute ICO keys
-	let mut keys: Vec<Ed25519KeyPair> = vec![];
-	if p2p_addr == "127.0.0.1:6000".parse::<net::SocketAddr>().unwrap() {
-		let key = key_pair::from_seed(0);
-		keys.push(key);
-	} else if p2p_addr == "127.0.0.1:6001".parse::<net::SocketAddr>().unwrap() {
-		let key = key_pair::from_seed(1);
-		keys.push(key);
-	} else if p2p_addr == "127.0.0.1:6002".parse::<net::SocketAddr>().unwrap() {
-		let key = key_pair::from_seed(2);
-		keys.push(key);
-	} else {
-		println!("Error: Unhandled IP address, no controlled keys assigned");
-	}
-	// start the generator
-    let (generator_ctx, generator, gn_finished_block_chan) = generator::new(&blockchain, keys);
-    let generator_worker_ctx = generator::worker::Worker::new(&server, gn_finished_block_chan, &mempool);
-    generator_ctx.start();
-    generator_worker_ctx.start();
-
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
-    miner_ctx.start();
-    miner_worker_ctx.start();
+    let worker_threads = worker_ctx.start();
 
     // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
+    if !config.p2p.connect.is_empty() {
+        let known_peers = config.p2p.connect.clone();
         let server = server.clone();
         thread::spawn(move || {
-            for peer in known_peers {
+            for addr in known_peers {
                 loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
                     match server.connect(addr) {
                         Ok(_) => {
                             info!("Connected to outgoing peer {}", &addr);
@@ -149,15 +267,50 @@ fn main() {
 
 
     // start the API server
+    let rpc_password = config.rpc.password_file.as_ref().map(|path| {
+        cli::read_first_line(&path.to_string_lossy()).unwrap_or_else(|e| {
+            error!("Error reading the RPC password: {}", e);
+            process::exit(1);
+        })
+    });
+    let rpc_access = api::rpc::Access{allow_ip: config.rpc.allow_ip.clone(), password: rpc_password};
     ApiServer::start(
         api_addr,
         &miner,
-		&generator,
+        &generator,
         &server,
         &blockchain,
+        &mempool,
+        &shutdown_tx,
+        rpc_access,
     );
 
-    loop {
-        std::thread::park();
+    // start mining and generating transactions if configured to
+    if config.miner.autostart {
+        miner.start(config.miner.lambda);
+    }
+    if config.generator.autostart {
+        generator.start(config.generator.theta);
+    }
+
+    shutdown_rx.recv().unwrap();
+    info!("Shutting down");
+    // stop producing blocks and transactions, their workers return once they handled the last ones
+    miner.exit();
+    generator.exit();
+    for handle in miner_threads.into_iter().chain(generator_threads) {
+        let _ = handle.join();
+    }
+    // handle the messages already received from peers, then stop reading from them
+    msg_chan.close();
+    for handle in worker_threads {
+        let _ = handle.join();
+    }
+    // the blockchain and the mempool live in memory, there is no storage to flush
+    info!("Chain stopped at tip {}", blockchain.lock().unwrap().tip());
+    // write what is queued for the peers, and close the connections
+    if !server.shutdown(time::Duration::from_secs(5)) {
+        warn!("Some peers were not closed in time");
     }
+    info!("Shut down");
 }
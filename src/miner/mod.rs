@@ -1,25 +1,37 @@
 pub mod worker;
+pub mod template;
+pub mod policy;
 
-use log::info;
+use log::{debug, info};
+use serde::Serialize;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::time;
+use crossbeam::select;
+use std::time::{self, Instant};
 
 use std::thread;
 
-use crate::types::block::{Block, Header, Content};
+use crate::types::block::{Block, Header, HeaderHasher, Content};
 use crate::types::hash::{H256, Hashable};
 use crate::blockchain::{State, Blockchain};
-use crate::types::transaction::{SignedTransaction, TransactionMempool};
+use crate::blockchain::time::system_time;
+use crate::types::transaction::TransactionMempool;
 use crate::types::merkle::MerkleTree;
-use rand::Rng;
+use policy::BlockPolicy;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-const MAX_TRX_PER_BLOCK: u32 = 25;
+/// Time to wait before checking the mempool again when the block policy says not to mine yet
+const POLICY_WAIT: time::Duration = time::Duration::from_millis(100);
+/// Hashes a mining thread computes between two checks of the cancellation flag, divides 2^32
+const HASHES_PER_CHECK: u64 = 1024;
+/// Interval at which the hashrate is sampled
+const HASHRATE_INTERVAL: time::Duration = time::Duration::from_secs(1);
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Mine(u64), // mine this number of blocks without waiting in between, then pause
+    Stop, // pause mining
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
     Exit,
 }
@@ -37,17 +49,42 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
 	blockchain: Arc<Mutex<Blockchain>>,
 	mempool: Arc<Mutex<TransactionMempool>>,
+	threads: usize, // number of threads searching the nonce space
+	hash_count: Arc<AtomicU64>, // total number of hashes computed
+	hashrate_sample: (Instant, u64), // time and hash count of the last hashrate sample
+	blocks_remaining: Option<u64>, // blocks left to mine before pausing, when started with `mine`
+	status: Arc<Mutex<Status>>,
+	policy: BlockPolicy,
+}
+
+/// What the miner is doing, as reported by `Handle::status`
+#[derive(Serialize, Clone, Debug)]
+pub struct Status {
+	pub state: String, // "paused", "running" or "shutdown"
+	pub lambda: u64, // interval between blocks when running
+	pub blocks_found: u64,
+	pub blocks_remaining: Option<u64>, // blocks left to mine before pausing, when started with `mine`
+	pub hashrate: u64, // hashes per second of all threads, sampled every second while mining
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+	status: Arc<Mutex<Status>>,
+	policy: BlockPolicy,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<TransactionMempool>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<TransactionMempool>>, threads: usize, policy: BlockPolicy) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+	let status = Arc::new(Mutex::new(Status{
+		state: "paused".to_string(),
+		lambda: 0,
+		blocks_found: 0,
+		blocks_remaining: None,
+		hashrate: 0,
+	}));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -55,10 +92,18 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<TransactionM
         finished_block_chan: finished_block_sender,
 		blockchain: Arc::clone(blockchain),
 		mempool: Arc::clone(mempool),
+		threads: threads.max(1),
+		hash_count: Arc::new(AtomicU64::new(0)),
+		hashrate_sample: (Instant::now(), 0),
+		blocks_remaining: None,
+		status: Arc::clone(&status),
+		policy: policy.clone(),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+		status,
+		policy,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -70,168 +115,296 @@ fn test_new() -> (Context, Handle, Receiver<Block>) {
     let blockchain = Arc::new(Mutex::new(blockchain));
 	let mempool = TransactionMempool::new();
 	let mempool = Arc::new(Mutex::new(mempool));
-    new(&blockchain, &mempool)
+    // without a generator, only empty blocks can be mined
+    new(&blockchain, &mempool, 2, BlockPolicy{mine_empty_blocks: true, ..Default::default()})
 }
 
+// Signals sent once the miner exited are dropped: while the node shuts down, the network worker still
+// reports new tips and API requests may still come in.
 impl Handle {
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Exit);
     }
 
     pub fn start(&self, lambda: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+        let _ = self.control_chan.send(ControlSignal::Start(lambda));
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Update);
     }
+
+	/// Mine `blocks` blocks as fast as possible, then pause
+	pub fn mine(&self, blocks: u64) {
+		let _ = self.control_chan.send(ControlSignal::Mine(blocks));
+	}
+
+	pub fn stop(&self) {
+		let _ = self.control_chan.send(ControlSignal::Stop);
+	}
+
+	pub fn status(&self) -> Status {
+		self.status.lock().unwrap().clone()
+	}
+
+	/// Policy the miner fills its blocks with
+	pub fn policy(&self) -> &BlockPolicy {
+		&self.policy
+	}
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        handle
     }
 
     fn miner_loop(&mut self) {
         // main mining loop
-		let mut parent_hash: H256;
+		let mut parent_hash: H256 = H256::default();
 		let mut difficulty: H256 = [255u8; 32].into();
 		let mut timestamp: u128;
-		let mut rng = rand::thread_rng();
-		let mut nonce: u32;
-		let mut state: State;
-
-		let blockchain = self.blockchain.lock().unwrap();
-		parent_hash = blockchain.tip();
-		state = blockchain.get_state(parent_hash).unwrap();
-		//difficulty = blockchain.get_block(parent_hash).unwrap().get_difficulty();
-		match blockchain.get_block(parent_hash) {
-			Ok(b) => difficulty = b.get_difficulty(),
-			Err(e) => println!("error getting block: {:?}", e),
-		}
-		drop(blockchain); // to release mutex lock
+		let mut min_timestamp: u128 = 0; // block timestamps must be after the median time past of the parent
+		let mut state: State = State::default();
+		let mut height: u32 = 0; // height of the block being mined
+		let mut pending_signal: Option<ControlSignal> = None; // signal that interrupted the last search
+		let mut refresh = true; // whether to move to the tip of the blockchain
 
 		loop {
             // check and react to control signals
-            match self.operating_state {
-                OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
-                    continue;
-                }
+            let signal = match self.operating_state {
+                OperatingState::Paused => Some(match pending_signal.take() {
+                    Some(signal) => signal,
+                    None => self.control_chan.recv().unwrap(),
+                }),
                 OperatingState::ShutDown => {
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                //unimplemented!()
-								let blockchain = self.blockchain.lock().unwrap();
-								parent_hash = blockchain.tip();
-								state = blockchain.get_state(parent_hash).unwrap();
-								match blockchain.get_block(parent_hash) {
-									Ok(b) => difficulty = b.get_difficulty(),
-									Err(e) => println!("error getting block: {:?}", e),
-								}
-							}
-                        };
-                    }
-                    Err(TryRecvError::Empty) => {}
+                _ => match pending_signal.take().map_or_else(|| self.control_chan.try_recv(), Ok) {
+                    Ok(signal) => Some(signal),
+                    Err(TryRecvError::Empty) => None,
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
+            };
+            if let Some(signal) = signal {
+                self.handle_signal(signal);
+                // the tip may have changed, or changed while paused
+                refresh = true;
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
+            match self.operating_state {
+                OperatingState::Paused => continue,
+                OperatingState::ShutDown => return,
+                OperatingState::Run(_) => {}
             }
 
-            // TODO for student: actual mining, create a block
-			let mut data: Vec<SignedTransaction> = vec![];
-			let mut i: u32 = 0;
-			let mut mempool = self.mempool.lock().unwrap(); // to acquire mutex lock
-			loop {
-				match mempool.dequeue() {
-					Ok(trx) => {
-						match state.update(&vec![trx.clone()]) {
-							Ok(s) => {state = s; data.push(trx)},
-							Err(_e) => {
-								//println!("received strx, hash:{},error:{}", trx.hash(), _e);
-								break;},
-						};
-					},
-					Err(_) => break,
+			if refresh {
+				let blockchain = self.blockchain.lock().unwrap();
+				parent_hash = blockchain.tip();
+				state = blockchain.get_state(parent_hash).unwrap();
+				height = blockchain.get_height(parent_hash).unwrap() + 1;
+				min_timestamp = blockchain.median_time_past(parent_hash).unwrap() + 1;
+				match blockchain.get_block(parent_hash) {
+					Ok(b) => difficulty = b.get_difficulty(),
+					Err(e) => println!("error getting block: {:?}", e),
 				}
-
-				i = i + 1;
-				if i >= MAX_TRX_PER_BLOCK { break; }
+				drop(blockchain); // to release mutex lock
+				refresh = false;
 			}
+
+            // TODO for student: actual mining, create a block
+			timestamp = system_time().max(min_timestamp);
+			let mut mempool = self.mempool.lock().unwrap(); // to acquire mutex lock
+			// lock times are checked against the median time past of the parent, not the block timestamp
+			let selected = self.policy.select(&mut mempool, &state, height, min_timestamp - 1);
 			drop(mempool); // to release mutex lock
 
-			if data.len()>0 { // mine a block only if there is atleast 1 trx
+			if let Some((data, next_state)) = selected {
 				//let root: H256 = hex!("4b3947f87e40c184f6394d4f0916a43b1395d51855e39b4ffe400b2be3797d98").into();
 				let merkle_tree: MerkleTree = MerkleTree::new(&data);
 				let root: H256 = merkle_tree.root();
 				let content: Content = Content{data: data};
-				let mut head: Header;
-				let mut block: Block;
-
-				loop {
-					timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-					nonce = rng.gen();
-					head = Header{
-						parent_hash: parent_hash,
-						nonce: nonce,
-						difficulty: difficulty,
-						timestamp: timestamp,
-						merkle_root: root
-					};
-					block = Block{header: head.clone(), content: content.clone()};
-
-					if block.hash() <= difficulty { break; }
-				}
+				let head = Header{
+					parent_hash: parent_hash,
+					nonce: 0,
+					extra_nonce: 0,
+					difficulty: difficulty,
+					timestamp: timestamp,
+					merkle_root: root
+				};
+				let block = match self.solve(head, min_timestamp) {
+					Ok(head) => Block{header: head, content},
+					Err(signal) => {
+						// the template may be stale, handle the signal and start over
+						pending_signal = Some(signal);
+						continue;
+					}
+				};
 				//println!("parent_hash: {}, block_hash: {}", parent_hash, block.hash());
 
 				// TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
 				self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+				self.mempool.lock().unwrap().remove_from_queue(&block.get_transaction_hashes());
+				state = next_state;
 				parent_hash = block.hash();
+				height += 1;
+				// the median time past of the new parent is at most its own timestamp
+				min_timestamp = block.header.timestamp + 1;
+				self.block_found();
+			} else {
+				// wait for transactions that satisfy the policy
+				thread::sleep(POLICY_WAIT);
 			}
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
                     let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+                    // a signal ends the wait early, so that a long lambda does not delay a shutdown
+                    if let Ok(signal) = self.control_chan.recv_timeout(interval) {
+                        pending_signal = Some(signal);
+                    }
                 }
             }
         }
     }
+
+	/// React to a control signal
+	fn handle_signal(&mut self, signal: ControlSignal) {
+		match signal {
+			ControlSignal::Exit => {
+				info!("Miner shutting down");
+				self.operating_state = OperatingState::ShutDown;
+				self.blocks_remaining = None;
+			}
+			ControlSignal::Start(i) => {
+				info!("Miner starting in continuous mode with lambda {}", i);
+				self.operating_state = OperatingState::Run(i);
+				self.blocks_remaining = None;
+			}
+			ControlSignal::Mine(n) => {
+				info!("Miner mining {} blocks", n);
+				self.operating_state = if n == 0 { OperatingState::Paused } else { OperatingState::Run(0) };
+				self.blocks_remaining = if n == 0 { None } else { Some(n) };
+			}
+			ControlSignal::Stop => {
+				info!("Miner paused");
+				self.operating_state = OperatingState::Paused;
+				self.blocks_remaining = None;
+			}
+			ControlSignal::Update => {}
+		}
+		self.update_status();
+	}
+
+	/// Count a mined block, and pause once the blocks asked with `mine` are mined
+	fn block_found(&mut self) {
+		self.status.lock().unwrap().blocks_found += 1;
+		match self.blocks_remaining {
+			Some(n) if n <= 1 => {
+				info!("Miner mined the requested blocks, pausing");
+				self.operating_state = OperatingState::Paused;
+				self.blocks_remaining = None;
+			}
+			Some(n) => self.blocks_remaining = Some(n - 1),
+			None => {}
+		}
+		self.update_status();
+	}
+
+	/// Publish the operating state in the status shared with the handles
+	fn update_status(&self) {
+		let mut status = self.status.lock().unwrap();
+		let (state, lambda) = match self.operating_state {
+			OperatingState::Paused => ("paused", 0),
+			OperatingState::Run(i) => ("running", i),
+			OperatingState::ShutDown => ("shutdown", 0),
+		};
+		status.state = state.to_string();
+		status.lambda = lambda;
+		status.blocks_remaining = self.blocks_remaining;
+		if !matches!(self.operating_state, OperatingState::Run(_)) {
+			status.hashrate = 0;
+		}
+	}
+
+	/// Search a nonce for the header on `self.threads` threads. Thread `t` searches the extra nonces
+	/// `t, t + threads, t + 2 * threads, ...`, trying all the nonces of one before moving to the
+	/// next, so the threads never hash the same header. The timestamp is refreshed between batches.
+	/// Returns the solved header, or the control signal that cancelled the search.
+	fn solve(&mut self, header: Header, min_timestamp: u128) -> Result<Header, ControlSignal> {
+		let cancel = Arc::new(AtomicBool::new(false));
+		let (solved_sender, solved_receiver) = unbounded();
+		let mut searchers = vec![];
+		for t in 0..self.threads {
+			let mut header = header.clone();
+			header.extra_nonce = t as u32;
+			header.nonce = 0;
+			let step = self.threads as u32;
+			let cancel = Arc::clone(&cancel);
+			let hash_count = Arc::clone(&self.hash_count);
+			let solved_sender = solved_sender.clone();
+			let searcher = thread::Builder::new()
+				.name(format!("miner-{}", t))
+				.spawn(move || {
+					let mut hasher = HeaderHasher::new(&header);
+					while !cancel.load(Ordering::Relaxed) {
+						header.timestamp = system_time().max(min_timestamp);
+						for _ in 0..HASHES_PER_CHECK {
+							if hasher.hash(header.timestamp, header.nonce) <= header.difficulty {
+								solved_sender.send(header).unwrap();
+								return;
+							}
+							header.nonce = header.nonce.wrapping_add(1);
+						}
+						hash_count.fetch_add(HASHES_PER_CHECK, Ordering::Relaxed);
+						if header.nonce == 0 {
+							// all nonces tried, HASHES_PER_CHECK divides the nonce space
+							header.extra_nonce = header.extra_nonce.wrapping_add(step);
+							hasher = HeaderHasher::new(&header);
+						}
+					}
+				})
+				.unwrap();
+			searchers.push(searcher);
+		}
+
+		let result = loop {
+			select! {
+				recv(solved_receiver) -> header => break Ok(header.unwrap()),
+				recv(self.control_chan) -> signal => match signal {
+					Ok(signal) => break Err(signal),
+					Err(_) => panic!("Miner control channel detached"),
+				},
+				default(HASHRATE_INTERVAL) => {},
+			}
+			self.sample_hashrate();
+		};
+		cancel.store(true, Ordering::Relaxed);
+		for searcher in searchers {
+			searcher.join().unwrap();
+		}
+		self.sample_hashrate();
+		result
+	}
+
+	/// Update the hashrate if the last sample is older than `HASHRATE_INTERVAL`
+	fn sample_hashrate(&mut self) {
+		let (last_time, last_count) = self.hashrate_sample;
+		let elapsed = last_time.elapsed();
+		if elapsed < HASHRATE_INTERVAL {
+			return;
+		}
+		let count = self.hash_count.load(Ordering::Relaxed);
+		let rate = (count - last_count) as f64 / elapsed.as_secs_f64();
+		self.status.lock().unwrap().hashrate = rate as u64;
+		self.hashrate_sample = (Instant::now(), count);
+		debug!("Miner hashrate: {} H/s", rate as u64);
+	}
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -240,6 +413,7 @@ impl Context {
 mod test {
     use ntest::timeout;
     use crate::types::hash::Hashable;
+    use crate::types::block::Header;
 
     #[test]
     #[timeout(60000)]
@@ -270,6 +444,40 @@ mod test {
             block_prev = block_next;
         }
     }
+
+	#[test]
+	#[timeout(60000)]
+	fn solve_on_threads_and_cancel() {
+		let (mut miner_ctx, miner_handle, _) = super::test_new();
+		let header = Header{difficulty: [255u8; 32].into(), ..Default::default()};
+		let solved = miner_ctx.solve(header, 10).ok().unwrap();
+		assert!(solved.hash() <= solved.difficulty);
+		assert!(solved.timestamp >= 10);
+
+		// no hash is below a zero difficulty, only the signal ends the search
+		let header = Header{difficulty: [0u8; 32].into(), ..Default::default()};
+		miner_handle.update();
+		assert!(matches!(miner_ctx.solve(header, 0), Err(super::ControlSignal::Update)));
+	}
+
+	#[test]
+	fn mine_blocks_then_pause() {
+		let (mut miner_ctx, miner_handle, _) = super::test_new();
+		assert_eq!(miner_handle.status().state, "paused");
+		miner_ctx.handle_signal(super::ControlSignal::Mine(2));
+		let status = miner_handle.status();
+		assert_eq!((status.state.as_str(), status.blocks_remaining), ("running", Some(2)));
+		miner_ctx.block_found();
+		assert_eq!(miner_handle.status().blocks_remaining, Some(1));
+		miner_ctx.block_found();
+		let status = miner_handle.status();
+		assert_eq!((status.state.as_str(), status.blocks_found, status.blocks_remaining), ("paused", 2, None));
+
+		miner_ctx.handle_signal(super::ControlSignal::Start(10));
+		assert_eq!((miner_handle.status().state.as_str(), miner_handle.status().lambda), ("running", 10));
+		miner_ctx.handle_signal(super::ControlSignal::Stop);
+		assert_eq!(miner_handle.status().state, "paused");
+	}
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,151 @@
+use crate::blockchain::{State, MAX_BLOCK_SIZE};
+use crate::blockchain::spec::ConsensusParams;
+use crate::error::TransactionError;
+use crate::types::amount::Amount;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{SignedTransaction, TransactionMempool, MAX_NONCE_GAP};
+
+/// Rules the miner follows to fill its blocks. They are local choices of the node, the consensus rules
+/// only bound the block size.
+#[derive(Debug, Clone)]
+pub struct BlockPolicy {
+	pub mine_empty_blocks: bool, // keep the chain growing when there are no transactions
+	pub min_transactions: usize, // wait for this many transactions before mining
+	pub max_transactions: usize,
+	pub max_block_size: usize, // serialized size in bytes, at most the consensus limit
+	pub min_fee: Amount, // transactions paying less are left in the mempool
+}
+
+impl Default for BlockPolicy {
+	fn default() -> Self {
+		BlockPolicy{
+			mine_empty_blocks: false,
+			min_transactions: 0,
+			max_transactions: usize::MAX,
+			max_block_size: MAX_BLOCK_SIZE,
+			min_fee: Amount::ZERO,
+		}
+	}
+}
+
+impl BlockPolicy {
+	/// Check that the policy can produce valid blocks under the rules `consensus`
+	pub fn validate(&self, consensus: &ConsensusParams) -> Result<(), String> {
+		if self.max_block_size > consensus.max_block_size {
+			return Err(format!("max block size {} is above the consensus limit {}", self.max_block_size, consensus.max_block_size));
+		}
+		if self.max_block_size < Block::default().size() {
+			return Err(format!("max block size {} can not fit a block header", self.max_block_size));
+		}
+		if self.min_transactions > self.max_transactions {
+			return Err(format!("min transactions {} is above max transactions {}", self.min_transactions, self.max_transactions));
+		}
+		Ok(())
+	}
+
+	/// Select transactions from the mempool, in queue order, for a block at `height` on top of `state`, whose
+	/// parent has the median time past `median_time_past`. Transactions paying less than the minimum fee or not
+	/// fitting in the block are skipped, and stay in the mempool, as do those waiting for one of the previous
+	/// `MAX_NONCE_GAP` nonces of their sender. Other transactions not applying to the state can not be mined
+	/// on top of it, they are taken out of the queue.
+	/// Returns the transactions and the resulting state, or `None` if the policy says not to mine yet.
+	pub fn select(&self, mempool: &mut TransactionMempool, state: &State, height: u32, median_time_past: u128) -> Option<(Vec<SignedTransaction>, State)> {
+		let mut stale: Vec<H256> = vec![];
+		let mut data: Vec<SignedTransaction> = vec![];
+		let mut next_state = state.clone();
+		let mut size = Block::default().size();
+		for trx in mempool.queued(height, median_time_past) {
+			if matches!(state.get_balance(trx.sender_address()), Ok((nonce, _)) if trx.account_nonce() <= nonce) {
+				stale.push(trx.hash());
+				continue;
+			}
+			if data.len() >= self.max_transactions {
+				continue;
+			}
+			if trx.fee() < self.min_fee {
+				continue;
+			}
+			let trx_size = bincode::serialized_size(&trx).unwrap() as usize;
+			if size + trx_size > self.max_block_size {
+				continue;
+			}
+			match next_state.update(&vec![trx.clone()], height, median_time_past) {
+				Ok(s) => {
+					next_state = s;
+					size += trx_size;
+					data.push(trx);
+				}
+				Err(TransactionError::InvalidNonce{expected, found}) if found > expected && found - expected < MAX_NONCE_GAP => {}
+				Err(_) => stale.push(trx.hash()),
+			}
+		}
+		mempool.remove_from_queue(&stale);
+
+		let min_transactions = if self.mine_empty_blocks { self.min_transactions } else { self.min_transactions.max(1) };
+		if data.len() < min_transactions {
+			return None;
+		}
+		Some((data, next_state))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::address::Address;
+	use crate::types::key_pair;
+	use crate::types::transaction::Transaction;
+	use ring::signature::KeyPair;
+
+	#[test]
+	fn select_follows_policy() {
+		let key = key_pair::from_seed(10);
+		let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+		let state = State::new(vec![(sender, (0, Amount::from(100)))]);
+		let receiver = Address::from_public_key_bytes(key_pair::from_seed(11).public_key().as_ref());
+		let signed = |nonce: u32, value: u64, fee: u64| {
+			let trx = Transaction{receiver, value: Amount::from(value), fee: Amount::from(fee), account_nonce: nonce, lock_height: None, lock_time: None};
+			SignedTransaction::new(trx, &key)
+		};
+		let mut mempool = TransactionMempool::new();
+		for (nonce, fee) in [(1, 5), (2, 0), (2, 5), (3, 5)].iter() {
+			mempool.insert(&signed(*nonce, 1, *fee), Some(&state)).unwrap();
+		}
+
+		let mut policy = BlockPolicy{min_fee: Amount::from(1), ..Default::default()};
+		let (data, state_after) = policy.select(&mut mempool, &state, 1, 0).unwrap();
+		assert_eq!(data.iter().map(|trx| trx.account_nonce()).collect::<Vec<u32>>(), vec![1, 2, 3]);
+		assert_eq!(state_after.get_balance(sender).unwrap(), (3, Amount::from(82)));
+		assert_eq!(mempool.pending_hashes().len(), 4);
+		// once mined, their nonces are used: they are taken out of the queue
+		policy.select(&mut mempool, &state_after, 2, 0);
+		assert!(mempool.pending_hashes().is_empty());
+		// a reorg puts them back
+		let disconnected: Vec<SignedTransaction> = [(1, 5), (2, 0), (2, 5), (3, 5)].iter().map(|(nonce, fee)| signed(*nonce, 1, *fee)).collect();
+		mempool.requeue(&disconnected);
+
+		policy.max_transactions = 2;
+		assert_eq!(policy.select(&mut mempool, &state, 1, 0).unwrap().0.len(), 2);
+		policy.min_transactions = 2;
+		policy.min_fee = Amount::from(10);
+		assert!(policy.select(&mut mempool, &state, 1, 0).is_none());
+
+		let mut policy = BlockPolicy::default();
+		assert!(policy.select(&mut TransactionMempool::new(), &state, 1, 0).is_none());
+		policy.mine_empty_blocks = true;
+		assert!(policy.select(&mut TransactionMempool::new(), &state, 1, 0).unwrap().0.is_empty());
+		policy.mine_empty_blocks = false;
+		policy.max_block_size = Block::default().size();
+		assert!(policy.select(&mut mempool, &state, 1, 0).is_none());
+		policy.max_block_size = 2 * MAX_BLOCK_SIZE;
+		assert!(policy.validate(&ConsensusParams::default()).is_err());
+
+		// a transaction that can not apply is taken out of the queue, one waiting for the previous nonce stays
+		let mut mempool = TransactionMempool::new();
+		let waiting = signed(2, 1, 5);
+		mempool.requeue(&[signed(1, 1000, 5), waiting.clone()]);
+		assert!(BlockPolicy::default().select(&mut mempool, &state, 1, 0).is_none());
+		assert_eq!(mempool.pending_hashes(), vec![waiting.hash()]);
+	}
+}
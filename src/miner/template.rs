@@ -0,0 +1,95 @@
+use serde::{Serialize, Deserialize};
+
+use crate::blockchain::Blockchain;
+use crate::error::StorageError;
+use crate::types::block::{Block, Header, Content};
+use crate::types::hash::H256;
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::{SignedTransaction, TransactionMempool};
+
+use super::policy::BlockPolicy;
+
+/// Everything an external miner needs to build a block on top of the current tip.
+/// Blocks solved from it are submitted with the merkle root identifying the template.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockTemplate {
+	pub parent_hash: H256,
+	pub height: u32, // height of the block to mine
+	pub difficulty: H256,
+	pub merkle_root: H256,
+	pub min_timestamp: u128, // the timestamp must be at least this, i.e. after the median time past
+	pub max_timestamp: u128, // and at most this, the network-adjusted time plus the allowed drift
+	pub transactions: Vec<SignedTransaction>,
+}
+
+impl BlockTemplate {
+	/// Build a template on the tip of `blockchain`. Transactions are selected from the mempool with the
+	/// block policy of the miner, and left in it, so that unused templates lose nothing.
+	/// An empty template is returned when the policy says not to mine yet.
+	pub fn new(blockchain: &Blockchain, mempool: &mut TransactionMempool, policy: &BlockPolicy) -> Result<Self, StorageError> {
+		let parent_hash = blockchain.tip();
+		let parent = blockchain.get_block(parent_hash)?;
+		let height = blockchain.get_height(parent_hash)? + 1;
+		let median_time_past = blockchain.median_time_past(parent_hash)?;
+		let min_timestamp = median_time_past + 1;
+		let max_timestamp = blockchain.network_time().now() + blockchain.consensus().max_future_block_time;
+
+		let state = blockchain.get_state(parent_hash)?;
+		let transactions: Vec<SignedTransaction> = match policy.select(mempool, &state, height, median_time_past) {
+			Some((data, _)) => data,
+			None => vec![],
+		};
+
+		Ok(BlockTemplate{
+			parent_hash,
+			height,
+			difficulty: parent.get_difficulty(),
+			merkle_root: MerkleTree::new(&transactions).root(),
+			min_timestamp,
+			max_timestamp,
+			transactions,
+		})
+	}
+
+	/// The block solved with the given header fields
+	pub fn to_block(&self, timestamp: u128, nonce: u32, extra_nonce: u32) -> Block {
+		Block{
+			header: Header{
+				parent_hash: self.parent_hash,
+				nonce,
+				extra_nonce,
+				difficulty: self.difficulty,
+				timestamp,
+				merkle_root: self.merkle_root,
+			},
+			content: Content{data: self.transactions.clone()},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::blockchain::time::system_time;
+	use crate::types::hash::Hashable;
+
+	#[test]
+	fn solved_template_is_accepted() {
+		let mut blockchain = Blockchain::new();
+		let mut mempool = TransactionMempool::new();
+		let template = BlockTemplate::new(&blockchain, &mut mempool, &BlockPolicy::default()).unwrap();
+		assert_eq!(template.parent_hash, blockchain.tip());
+		assert_eq!(template.height, 1);
+		assert!(template.min_timestamp <= template.max_timestamp);
+
+		let timestamp = system_time().max(template.min_timestamp);
+		let mut nonce: u32 = 0;
+		let mut block = template.to_block(timestamp, nonce, 0);
+		while block.hash() > template.difficulty {
+			nonce += 1;
+			block = template.to_block(timestamp, nonce, 0);
+		}
+		blockchain.insert(&block).unwrap();
+		assert_eq!(blockchain.tip(), block.hash());
+	}
+}
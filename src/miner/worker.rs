@@ -1,6 +1,6 @@
 #![allow(unused)]
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use log::{debug, error, info};
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use std::thread;
@@ -8,12 +8,14 @@ use std::sync::{Arc, Mutex};
 use crate::blockchain::Blockchain;
 use crate::network::message::Message;
 use crate::types::hash::Hashable;
+use crate::miner::Handle as MinerHandle;
 
 #[derive(Clone)]
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
 	blockchain: Arc<Mutex<Blockchain>>,
+	miner: MinerHandle,
 }
 
 impl Worker {
@@ -21,30 +23,42 @@ impl Worker {
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
 		blockchain: &Arc<Mutex<Blockchain>>,
+		miner: &MinerHandle,
     ) -> Self {
         Self {
             server: server.clone(),
             finished_block_chan,
 			blockchain: Arc::clone(blockchain),
+			miner: miner.clone(),
         }
     }
 
-    pub fn start(self) {
-        thread::Builder::new()
+    pub fn start(self) -> thread::JoinHandle<()> {
+        let handle = thread::Builder::new()
             .name("miner-worker".to_string())
             .spawn(move || {
                 self.worker_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        handle
     }
 
     fn worker_loop(&self) {
-        loop {
-            let _block = self.finished_block_chan.recv().expect("Receive finished block error");
+        // the channel disconnects when the miner exits
+        while let Ok(_block) = self.finished_block_chan.recv() {
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
 			let mut blockchain = self.blockchain.lock().unwrap();
-			blockchain.insert(&_block);
+			if let Err(e) = blockchain.insert(&_block) {
+				error!("mined an invalid block {}: {}", _block.hash(), e);
+				// the miner builds on top of it, get it back to the tip
+				self.miner.update();
+				continue;
+			}
+			if blockchain.tip() != _block.hash() {
+				// a block from the network won the race, the miner must move to the new tip
+				self.miner.update();
+			}
 			// broadcast the block hash
 			self.server.broadcast(Message::NewBlockHashes(vec![_block.hash()]));
         }
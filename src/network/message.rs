@@ -12,4 +12,5 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    Version(u128), // sent on connection, carries the sender's clock in ms
 }
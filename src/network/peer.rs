@@ -5,17 +5,19 @@ use smol::Async;
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
+    direction: Direction,
 ) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
     let (write_sender, write_receiver) = mpsc::unbounded();
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -24,6 +26,7 @@ pub enum Direction {
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    direction: Direction, // who opened the connection
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
 }
 
@@ -42,15 +45,30 @@ impl Handle {
         });
     }
 
+    /// Stop queueing messages, the ones already queued are still written before the connection is closed
+    pub fn close(&self) {
+        self.write_queue.close_channel();
+    }
+
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
+        Self::test_handle_at(std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321))
+    }
+
+    #[cfg(any(test,test_utilities))]
+    pub fn test_handle_at(addr: std::net::SocketAddr) -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
-            addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            addr,
+            direction: Direction::Incoming,
             write_queue: s,
         },
         TestReceiver {
@@ -66,4 +84,12 @@ impl TestReceiver {
         let msg: Message = bincode::deserialize(&bytes).unwrap();
         msg
     }
+
+    /// The next message if one was already written, without waiting
+    pub fn try_recv(&mut self) -> Option<Message> {
+        match self.r.try_next() {
+            Ok(Some(bytes)) => Some(bincode::deserialize(&bytes).unwrap()),
+            _ => None,
+        }
+    }
 }
\ No newline at end of file
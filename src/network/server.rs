@@ -1,4 +1,5 @@
 use crate::types::address::Address;
+use crate::blockchain::time::system_time;
 use super::peer;
 use super::message;
 
@@ -6,16 +7,21 @@ use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
-use smol::{Async, Executor};
+use smol::{Async, Executor, Timer};
 use log::{debug, info, trace};
 use std::net;
 use std::sync::Arc;
 use std::thread;
+use std::time;
 
+/// Frames larger than this are not read, and we stop reading from the peer
+const MAX_MESSAGE_SIZE: u32 = 32 * 1024 * 1024;
 
+/// Create a server speaking on the network identified by `magic`, see `ChainSpec::magic`
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    magic: [u8; 4],
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
     let handle = Handle {
@@ -24,9 +30,12 @@ pub fn new(
     let ctx = Context {
         peers: std::collections::HashMap::new(),
         addr,
+        magic,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        banned: std::collections::HashSet::new(),
+        closing: None,
     };
     Ok((ctx, handle))
 }
@@ -34,9 +43,12 @@ pub fn new(
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
     addr: std::net::SocketAddr,
+    magic: [u8; 4], // first bytes of every frame
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    banned: std::collections::HashSet<net::IpAddr>, // hosts we no longer talk to, see `Handle::ban`
+    closing: Option<oneshot::Sender<()>>, // notified once every peer is closed, after a shutdown
 }
 
 impl Context {
@@ -82,7 +94,11 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
+                    let handle = if self.banned.contains(&addr.ip()) {
+                        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "peer host is banned"))
+                    } else {
+                        self.connect(&addr, ex.clone()).await
+                    };
                     result_chan.send(handle).unwrap();
                 }
                 ControlSignal::BroadcastMessage(msg) => {
@@ -93,12 +109,51 @@ impl Context {
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
+                    if self.closing.is_some() {
+                        // shutting down, the stream is dropped and the connection closed
+                        continue;
+                    }
+                    if let Ok(addr) = stream.get_ref().peer_addr() {
+                        if self.banned.contains(&addr.ip()) {
+                            info!("Refusing peer {} from a banned host", addr);
+                            continue;
+                        }
+                    }
                     self.accept(stream, ex.clone()).await?;
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
                     self.peers.remove(&addr);
                     info!("Peer {} disconnected", addr);
+                    if self.peers.is_empty() {
+                        if let Some(closed) = self.closing.take() {
+                            let _ = closed.send(());
+                        }
+                    }
+                }
+                ControlSignal::Ban(ip) => {
+                    trace!("Processing Ban({})", ip);
+                    self.banned.insert(ip);
+                    for (addr, hd) in self.peers.iter() {
+                        if addr.ip() == ip {
+                            hd.close();
+                        }
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let _ = result_chan.send(self.peers.values().cloned().collect());
+                }
+                ControlSignal::Shutdown(closed) => {
+                    trace!("Processing Shutdown command");
+                    for (_, hd) in self.peers.iter() {
+                        hd.close();
+                    }
+                    if self.peers.is_empty() {
+                        let _ = closed.send(());
+                    } else {
+                        self.closing = Some(closed);
+                    }
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     unimplemented!()
@@ -133,33 +188,40 @@ impl Context {
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (mut write_queue, handle) = peer::new(&stream, direction)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        let magic = self.magic;
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
         let mut reader = BufReader::new(stream.clone());
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
+            // the buffer to store the frame header, which contains the network magic and the length of the frame
+            let mut header_buffer: [u8; 8] = [0; 8];
             // the buffer to store the message content
             let mut msg_buffer: Vec<u8> = vec![];
             loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
-                        break;
-                    }
-                };
+                // first, read exactly 8 bytes to get the frame header
+                if reader.read_exact(&mut header_buffer).await.is_err() {
+                    break;
+                }
+                if header_buffer[0..4] != magic {
+                    info!("Peer {} is on another network, disconnecting", addr);
+                    break;
+                }
+                let msg_size = u32::from_be_bytes([header_buffer[4], header_buffer[5], header_buffer[6], header_buffer[7]]);
+                if msg_size > MAX_MESSAGE_SIZE {
+                    debug!("Peer {} sent a frame of {} bytes, disconnecting", addr, msg_size);
+                    break;
+                }
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
@@ -170,17 +232,18 @@ impl Context {
                 {
                     Ok(_) => {
                         let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
+                        // the channel is closed when the node shuts down
+                        if new_msg_chan.send((new_payload, handle_copy.clone())).await.is_err() {
+                            break;
+                        }
                     }
                     Err(_) => {
                         break;
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, or we stop talking to it: close the connection so that the writer stops too
+            let _ = reader.get_ref().get_ref().shutdown(net::Shutdown::Both);
         })
             .detach();
 
@@ -188,14 +251,19 @@ impl Context {
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
             loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+                // first, get a message to write from the queue, it ends once the peer is closed
+                let new_msg = match write_queue.next().await {
+                    Some(msg) => msg,
+                    None => break,
+                };
 
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
+                // second, encode the network magic and the length of the message
+                let mut header_buffer: [u8; 8] = [0; 8];
+                header_buffer[0..4].copy_from_slice(&magic);
+                header_buffer[4..8].copy_from_slice(&(new_msg.len() as u32).to_be_bytes());
 
                 // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
+                match writer.write_all(&header_buffer).await {
                     Ok(_) => {}
                     Err(_) => {
                         break;
@@ -214,7 +282,8 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected or closed: close the connection so that the reader stops too
+            let _ = writer.get_ref().get_ref().shutdown(net::Shutdown::Both);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -222,6 +291,9 @@ impl Context {
         })
             .detach();
 
+        // introduce ourselves, so that the peer learns our clock offset
+        handle.clone().write(message::Message::Version(system_time()));
+
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
         Ok(handle)
@@ -267,6 +339,34 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Disconnect the peers at `ip`, and refuse connections from and to it from now on
+    pub fn ban(&self, ip: net::IpAddr) {
+        let _ = smol::block_on(self.control_chan.send(ControlSignal::Ban(ip)));
+    }
+
+    /// The connected peers
+    pub fn peers(&self) -> Vec<peer::Handle> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Close every peer once the messages queued for it are written, and stop accepting new ones.
+    /// Returns whether all peers were closed within `timeout`.
+    pub fn shutdown(&self, timeout: time::Duration) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        if smol::block_on(self.control_chan.send(ControlSignal::Shutdown(sender))).is_err() {
+            return true;
+        }
+        smol::block_on(smol::future::or(
+            async { receiver.await.is_ok() },
+            async {
+                Timer::after(timeout).await;
+                false
+            },
+        ))
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
@@ -285,4 +385,7 @@ enum ControlSignal {
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
     SendToPeer((Address,message::Message)),
+    GetPeers(oneshot::Sender<Vec<peer::Handle>>),
+    Ban(net::IpAddr),
+    Shutdown(oneshot::Sender<()>),
 }
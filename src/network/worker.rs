@@ -3,13 +3,17 @@ use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
 use crate::blockchain::{Blockchain, State};
+use crate::blockchain::time::NetworkTime;
+use crate::miner::Handle as MinerHandle;
 use crate::types::block::Block;
 use crate::types::hash::{H256, Hashable};
 use crate::types::transaction::{SignedTransaction, TransactionMempool};
+use crate::error::{BlockError, NetworkError, BAN_THRESHOLD};
 
 use log::{debug, warn, error};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::collections::{HashSet, HashMap};
@@ -25,6 +29,9 @@ pub struct Worker {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     tx_mempool: Arc<Mutex<TransactionMempool>>,
+    peer_scores: Arc<Mutex<HashMap<IpAddr, u32>>>, // ban score of each peer host, shared by all worker threads
+    network_time: NetworkTime,
+    miner: MinerHandle, // notified when a received block changes the tip
 }
 
 
@@ -34,7 +41,8 @@ impl Worker {
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
-		tx_mempool: &Arc<Mutex<TransactionMempool>>
+		tx_mempool: &Arc<Mutex<TransactionMempool>>,
+        miner: &MinerHandle,
     ) -> Self {
         Self {
             msg_chan: msg_src,
@@ -42,33 +50,73 @@ impl Worker {
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
             tx_mempool: Arc::clone(tx_mempool),
+            peer_scores: Arc::new(Mutex::new(HashMap::new())),
+            network_time: blockchain.lock().unwrap().network_time(),
+            miner: miner.clone(),
         }
     }
 
-    pub fn start(self) {
+    /// Penalise a peer for sending an invalid message. Scores are kept per host, so that reconnecting from
+    /// another port does not reset them. Once a host reaches `BAN_THRESHOLD`, the peer is disconnected and
+    /// the server refuses further connections from it.
+    fn misbehaving(&self, peer: &peer::Handle, e: &NetworkError) {
+        let score = e.ban_score();
+        warn!("peer {} sent {}", peer.addr(), e);
+        if score == 0 {
+            return;
+        }
+        let mut peer_scores = self.peer_scores.lock().unwrap();
+        let peer_score = peer_scores.entry(peer.addr().ip()).or_insert(0);
+        let banned = *peer_score >= BAN_THRESHOLD;
+        *peer_score = peer_score.saturating_add(score);
+        if !banned && *peer_score >= BAN_THRESHOLD {
+            warn!("peer {} reached ban score {}, disconnecting and banning {}", peer.addr(), peer_score, peer.addr().ip());
+            peer.close();
+            self.server.ban(peer.addr().ip());
+        }
+    }
+
+    fn is_banned(&self, peer: &peer::Handle) -> bool {
+        let peer_scores = self.peer_scores.lock().unwrap();
+        peer_scores.get(&peer.addr().ip()).copied().unwrap_or(0) >= BAN_THRESHOLD
+    }
+
+    /// Spawn the worker threads. They return once the message channel is closed and drained.
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
         let num_worker = self.num_worker;
+        let mut handles = vec![];
         for i in 0..num_worker {
             let cloned = self.clone();
-            thread::spawn(move || {
+            handles.push(thread::spawn(move || {
                 cloned.worker_loop();
                 warn!("Worker thread {} exited", i);
-            });
+            }));
         }
+        handles
     }
 
     fn worker_loop(&self) {
         loop {
             let result = smol::block_on(self.msg_chan.recv());
             if let Err(e) = result {
-                error!("network worker terminated {}", e);
+                debug!("network worker terminated {}", e);
                 break;
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            if self.is_banned(&peer) {
+                continue;
+            }
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.misbehaving(&peer, &NetworkError::from(e));
+                    continue;
+                }
+            };
             let mut locked_blockchain = self.blockchain.lock().unwrap();
             let mut locked_mempool = self.tx_mempool.lock().unwrap();
-			let mut buffer: HashMap<H256, Block> = HashMap::new();
+			let mut buffer: HashMap<H256, (Block, peer::Handle)> = HashMap::new(); // orphan blocks, with the peer that sent them
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -77,6 +125,10 @@ impl Worker {
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
                 }
+                Message::Version(peer_time) => {
+                    debug!("Version: peer {} time {}", peer.addr(), peer_time);
+                    self.network_time.add_sample(peer.addr().ip(), peer_time);
+                }
 
                 Message::NewBlockHashes(hashes) => {
 					// hashes: Vec<H256>
@@ -104,10 +156,10 @@ impl Worker {
 							debug!("Adding blockhash {} to available_blocks", hash);
 							match locked_blockchain.get_block(hash) {
 								Ok(block) => available_blocks.push(block),
-								Err(e) => debug!("error getting block: {:?}", e),
+								Err(e) => debug!("error getting block: {}", e),
 							}
 						} else if buffer.contains_key(&hash) {
-							available_blocks.push(buffer[&hash].clone());
+							available_blocks.push(buffer[&hash].0.clone());
 						}
                     }
                     if available_blocks.len() !=0{
@@ -118,49 +170,42 @@ impl Worker {
 
                 Message::Blocks(vec_blocks)=>{
                     debug!("Received Blocks Message");
+					let old_tip = locked_blockchain.tip();
 					let mut get_block_hash: Vec<H256> = vec![];
 					let mut new_block_hashes: Vec<H256> = vec![];
-					let (mut hash, mut difficulty, mut parent_hash): (H256, H256, H256);
-					let mut is_parent_present: bool;
-					let mut parent_state: State;
+					let mut hash: H256;
 					for block in vec_blocks{
-                        let mut block_is_valid: bool = true;
-                        for tx in &block.content.data{ // Transaction signature check
-                            if !tx.verify(){
-                                block_is_valid = false;
-                                debug!("Invalid transaction in the block!");
-                                break;
-                            }
+                        hash = block.hash();
+                        if locked_blockchain.is_hash_present(hash) {
+                            debug!("received block is already present");
+                            continue;
                         }
-                        if block_is_valid{
-                            hash = block.hash();
-                            difficulty = block.get_difficulty();
-                            if !(hash<=difficulty) || locked_blockchain.is_hash_present(hash) {
-                                debug!("received block is either invalid or already present");
-                                continue;
+                        // all validation rules are applied by the blockchain
+                        match locked_blockchain.insert(&block) {
+                            Ok(()) => {
+                                new_block_hashes.push(hash); // Send Message::NewBlockHashes to broadcast new block
+                                locked_mempool.remove_from_queue(&block.get_transaction_hashes());
                             }
-                            parent_hash = block.get_parent();
-                            is_parent_present = locked_blockchain.is_hash_present(parent_hash);
-                            if is_parent_present && difficulty==locked_blockchain.get_block(parent_hash).unwrap().get_difficulty() {
-								parent_state = locked_blockchain.get_state(parent_hash).unwrap();
-								match parent_state.update(&block.content.data) {
-									Ok(state) => {locked_blockchain.insert(&block); new_block_hashes.push(hash)},
-									Err(e) => continue,
-								}
-                                //locked_blockchain.insert(&block);
-                                //new_block_hashes.push(hash); // Send Message::NewBlockHashes to broadcast new block
-                            } else if !is_parent_present && !buffer.contains_key(&hash) {
+                            Err(BlockError::UnknownParent(parent_hash)) => {
+                                if buffer.contains_key(&hash) {
+                                    continue;
+                                }
                                 get_block_hash.push(parent_hash); // Send Message::GetBlocks for parent of orphan block
-                                buffer.insert(hash, block.clone());
+                                buffer.insert(hash, (block.clone(), peer.clone()));
                                 new_block_hashes.push(hash); // Send Message::NewBlockHashes to broadcast new block
                             }
-                            for signed_tx in &block.content.data{
-                                let signed_tx_hash = signed_tx.hash();
-								if !locked_mempool.is_hash_present(signed_tx_hash) {
-									locked_mempool.insert(signed_tx, false);
-								}
+                            Err(e) => {
+                                self.misbehaving(&peer, &NetworkError::from(e));
+                                continue;
                             }
                         }
+                        for signed_tx in &block.content.data{
+                            let signed_tx_hash = signed_tx.hash();
+							if !locked_mempool.is_hash_present(signed_tx_hash) {
+								// signatures were checked with the block
+								let _ = locked_mempool.insert(signed_tx, None);
+							}
+                        }
                     }
 					if get_block_hash.len()>0 {
 						// deduplicate the vector of parent hashes
@@ -174,25 +219,40 @@ impl Worker {
 						self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
 						//peer.write(Message::NewBlockHashes(new_block_hashes));
 					}
-					let mut inserted_hashes: Vec<H256> = vec![];
-					let mut something_inserted: bool;
+					let mut processed_hashes: Vec<H256> = vec![];
 					while buffer.len()>0 {
-						something_inserted = false;
-						for (hash, block) in buffer.iter() {
-							parent_hash = block.get_parent();
-							if locked_blockchain.is_hash_present(parent_hash) && block.get_difficulty()==locked_blockchain.get_block(parent_hash).unwrap().get_difficulty() {
-								locked_blockchain.insert(&block);
-								inserted_hashes.push(*hash);
-								something_inserted = true;
+						for (hash, (block, sender)) in buffer.iter() {
+							match locked_blockchain.insert(&block) {
+								Ok(()) => {
+									locked_mempool.remove_from_queue(&block.get_transaction_hashes());
+									processed_hashes.push(*hash);
+								}
+								Err(BlockError::UnknownParent(_)) => {},
+								Err(e) => {
+									// drop it, so that an invalid block does not stay in the buffer.
+									// The peer that sent the orphan is to blame, not the one that sent its parent.
+									self.misbehaving(sender, &NetworkError::from(e));
+									processed_hashes.push(*hash);
+								}
 							}
 						}
-						if !something_inserted {
+						if processed_hashes.is_empty() {
 							break
-						} else {
-							for hash in inserted_hashes.iter() {
-								buffer.remove(hash);
-							}
 						}
+						for hash in processed_hashes.drain(..) {
+							buffer.remove(&hash);
+						}
+					}
+					if locked_blockchain.tip() != old_tip {
+						// the transactions of the blocks a reorg took out of the longest chain wait to be mined again
+						let disconnected: Vec<SignedTransaction> = locked_blockchain.disconnected_blocks(old_tip).iter()
+							.flat_map(|block| block.content.data.iter())
+							.filter(|trx| locked_blockchain.find_transaction(trx.hash()).is_err())
+							.cloned()
+							.collect();
+						locked_mempool.requeue(&disconnected);
+						// the miner is working on a stale parent
+						self.miner.update();
 					}
 				}
                 /*
@@ -223,11 +283,9 @@ impl Worker {
 					debug!("Received GetTransactions");
 
                     for hash in tx_hashes{
-						if locked_mempool.is_hash_present(hash) {
-							let signed_trx = locked_mempool.get_transaction(hash).unwrap();
-							send_trx.push(signed_trx);
-						} else {
-							debug!(" tx hash {} is not in mempool", hash);
+						match locked_mempool.get_transaction(hash) {
+							Ok(signed_trx) => send_trx.push(signed_trx),
+							Err(e) => debug!("{}", e),
 						}
                     }
 
@@ -240,15 +298,16 @@ impl Worker {
 				Message::Transactions(vec_signed_transaction) => {
 					debug!("Recieved Transactions");
                     let mut tx_to_broadcast:Vec<H256> = vec![];
+                    let tip_state = locked_blockchain.get_state(locked_blockchain.tip()).unwrap();
                     for signed_trx in vec_signed_transaction{
-                        if signed_trx.verify(){
-                            let hash = signed_trx.hash();
-							if locked_mempool.is_hash_present(hash) {
-								debug!("tx {} already in the memepool", hash);
-							} else {
-								locked_mempool.insert(&signed_trx, true);
-								tx_to_broadcast.push(hash);
-							}
+                        let hash = signed_trx.hash();
+                        if locked_mempool.is_hash_present(hash) {
+                            debug!("tx {} already in the memepool", hash);
+                            continue;
+                        }
+                        match locked_mempool.insert(&signed_trx, Some(&tip_state)) {
+                            Ok(()) => tx_to_broadcast.push(hash),
+                            Err(e) => self.misbehaving(&peer, &NetworkError::from(e)),
                         }
                     }
                     if tx_to_broadcast.len() != 0{
@@ -277,6 +336,13 @@ impl TestMsgSender {
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
     }
+
+    /// send raw bytes from a peer at another address
+    fn send_raw(&self, bytes: Vec<u8>, addr: SocketAddr) -> PeerTestReceiver {
+        let (handle, r) = peer::Handle::test_handle_at(addr);
+        smol::block_on(self.s.send((bytes, handle))).unwrap();
+        r
+    }
 }
 #[cfg(any(test,test_utilities))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
@@ -288,7 +354,9 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     let blockchain = Arc::new(Mutex::new(blockchain));
 	let tx_mempool = TransactionMempool::new();
 	let tx_mempool = Arc::new(Mutex::new(tx_mempool));
-	let worker = Worker::new(1, msg_chan, &server, &blockchain, &tx_mempool);
+	let (miner_ctx, miner, _) = crate::miner::new(&blockchain, &tx_mempool, 1, Default::default());
+	miner_ctx.start(); // paused, it only has to receive the updates
+	let worker = Worker::new(1, msg_chan, &server, &blockchain, &tx_mempool, &miner);
     worker.start(); 
     (test_msg_sender, server_receiver, longest_chain_hashes)
 }
@@ -345,6 +413,26 @@ mod test {
             panic!();
         }
     }
+    #[test]
+    #[timeout(60000)]
+    fn undecodable_message_does_not_stop_worker() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let _bad_peer_receiver = test_msg_sender.send_raw(vec![0xff; 7], "127.0.0.2:12322".parse().unwrap());
+        let h = *v.last().unwrap();
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![h]));
+        let reply = peer_receiver.recv();
+        if let Message::Blocks(v) = reply {
+            assert_eq!(h, v[0].hash())
+        } else {
+            panic!();
+        }
+        // the host of the bad peer is banned, whatever the port
+        let bytes = bincode::serialize(&Message::GetBlocks(vec![h])).unwrap();
+        let mut reconnected_receiver = test_msg_sender.send_raw(bytes, "127.0.0.2:12323".parse().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![h]));
+        peer_receiver.recv();
+        assert!(reconnected_receiver.try_recv().is_none());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
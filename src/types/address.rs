@@ -1,6 +1,7 @@
 #![allow(unused)]
 use serde::{Serialize, Deserialize};
 use ring::digest::{Context, Digest, SHA256};
+use crate::error::AddressError;
 //use bincode::deserialize;
 //use data_encoding::HEXLOWER;
 
@@ -22,6 +23,12 @@ impl std::convert::From<[u8; 20]> for Address {
     }
 }
 
+impl std::convert::AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::fmt::Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let start = if let Some(precision) = f.precision() {
@@ -67,12 +74,132 @@ impl Address {
 		return address;
 		//return Address([0; 20]);
 	}
+
+	/// Derive the address of an M-of-N multisig account from its public keys and threshold.
+	/// The keys are sorted and deduplicated first, so the order in which they are given does not matter.
+	pub fn from_multisig(threshold: u8, public_keys: &[Vec<u8>]) -> Address {
+		let mut keys: Vec<Vec<u8>> = public_keys.to_vec();
+		keys.sort();
+		keys.dedup();
+
+		let mut context = Context::new(&SHA256);
+		context.update(b"multisig");
+		context.update(&[threshold, keys.len() as u8]);
+		for key in keys.iter() {
+			context.update(key);
+		}
+		let digest = context.finish();
+		let mut buffer: [u8; 20] = [0; 20];
+		buffer.copy_from_slice(&digest.as_ref()[12..32]);
+
+		Address(buffer)
+	}
+
+	/// The address with the version byte `version` of a network, to be written in Base58Check
+	pub fn encode(&self, version: u8) -> EncodedAddress {
+		EncodedAddress{version, address: *self}
+	}
+}
+
+/// An address with the version byte of its network. It is written in Base58Check: the version, the
+/// 20 bytes of the address and a 4-byte checksum (the first bytes of a double SHA256), so that a mistyped
+/// address is rejected instead of receiving coins no one can spend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodedAddress {
+	pub version: u8,
+	pub address: Address,
+}
+
+impl EncodedAddress {
+	/// The address, if it belongs to the network with version byte `version`
+	pub fn for_version(&self, version: u8) -> Result<Address, AddressError> {
+		if self.version != version {
+			return Err(AddressError::WrongVersion{expected: version, found: self.version});
+		}
+		Ok(self.address)
+	}
+}
+
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+	let first = ring::digest::digest(&SHA256, payload);
+	let second = ring::digest::digest(&SHA256, first.as_ref());
+	let mut checksum: [u8; 4] = [0; 4];
+	checksum.copy_from_slice(&second.as_ref()[0..4]);
+	checksum
+}
+
+impl std::fmt::Display for EncodedAddress {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let mut payload: Vec<u8> = vec![self.version];
+		payload.extend_from_slice(&self.address.0);
+		let checksum = checksum(&payload);
+		payload.extend_from_slice(&checksum);
+
+		// base58 digits, least significant first
+		let mut digits: Vec<u8> = vec![];
+		for byte in payload.iter() {
+			let mut carry = *byte as u32;
+			for digit in digits.iter_mut() {
+				carry += (*digit as u32) << 8;
+				*digit = (carry % 58) as u8;
+				carry /= 58;
+			}
+			while carry > 0 {
+				digits.push((carry % 58) as u8);
+				carry /= 58;
+			}
+		}
+		// each leading zero byte is written as a '1'
+		let zeros = payload.iter().take_while(|byte| **byte == 0).count();
+		let encoded: String = std::iter::repeat_n('1', zeros)
+			.chain(digits.iter().rev().map(|digit| BASE58_ALPHABET[*digit as usize] as char))
+			.collect();
+		write!(f, "{}", encoded)
+	}
+}
+
+impl std::str::FromStr for EncodedAddress {
+	type Err = AddressError;
+
+	fn from_str(s: &str) -> Result<EncodedAddress, Self::Err> {
+		// bytes, least significant first
+		let mut bytes: Vec<u8> = vec![];
+		for c in s.chars() {
+			let mut carry = match BASE58_ALPHABET.iter().position(|a| *a as char == c) {
+				Some(value) => value as u32,
+				None => return Err(AddressError::InvalidCharacter(c)),
+			};
+			for byte in bytes.iter_mut() {
+				carry += (*byte as u32) * 58;
+				*byte = (carry & 0xff) as u8;
+				carry >>= 8;
+			}
+			while carry > 0 {
+				bytes.push((carry & 0xff) as u8);
+				carry >>= 8;
+			}
+		}
+		let zeros = s.chars().take_while(|c| *c == '1').count();
+		let payload: Vec<u8> = std::iter::repeat_n(0, zeros).chain(bytes.into_iter().rev()).collect();
+		if payload.len() != 25 {
+			return Err(AddressError::InvalidLength(payload.len()));
+		}
+		if checksum(&payload[0..21]) != payload[21..25] {
+			return Err(AddressError::InvalidChecksum);
+		}
+		let mut buffer: [u8; 20] = [0; 20];
+		buffer.copy_from_slice(&payload[1..21]);
+		Ok(EncodedAddress{version: payload[0], address: Address(buffer)})
+	}
 }
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod test {
-    use super::Address;
+    use super::{Address, EncodedAddress};
+    use crate::error::AddressError;
 
     #[test]
     fn from_a_test_key() {
@@ -84,6 +211,24 @@ mod test {
         // "0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d"
         // take the last 20 bytes, we get "1851a0eae0060a132cf0f64a0ffaea248de6cba0"
     }
+
+    #[test]
+    fn base58check_encoding() {
+        assert_eq!(Address::default().encode(0).to_string(), "1111111111111111111114oLvT2");
+        let addr: Address = hex!("1851a0eae0060a132cf0f64a0ffaea248de6cba0").into();
+        let encoded = addr.encode(0x6f).to_string();
+        let decoded = encoded.parse::<EncodedAddress>().unwrap();
+        assert_eq!(decoded.for_version(0x6f), Ok(addr));
+        assert_eq!(decoded.for_version(0), Err(AddressError::WrongVersion{expected: 0, found: 0x6f}));
+
+        // a typo breaks the checksum
+        let mut typo: Vec<char> = encoded.chars().collect();
+        typo[5] = if typo[5] == 'a' { 'b' } else { 'a' };
+        let typo: String = typo.into_iter().collect();
+        assert_eq!(typo.parse::<EncodedAddress>(), Err(AddressError::InvalidChecksum));
+        assert_eq!("1O".parse::<EncodedAddress>(), Err(AddressError::InvalidCharacter('O')));
+        assert_eq!("11".parse::<EncodedAddress>(), Err(AddressError::InvalidLength(2)));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,71 @@
+use serde::{Serialize, Deserialize};
+
+/// An amount of coins. Arithmetic is checked, so that overflows surface as validation errors instead of
+/// panicking in debug builds or wrapping around in release builds.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Copy, Hash, Default, Debug)]
+pub struct Amount(u64);
+
+impl Amount {
+	pub const ZERO: Amount = Amount(0);
+	pub const MAX: Amount = Amount(u64::MAX);
+
+	pub const fn from_u64(value: u64) -> Amount {
+		Amount(value)
+	}
+
+	pub fn as_u64(&self) -> u64 {
+		self.0
+	}
+
+	pub fn is_zero(&self) -> bool {
+		self.0 == 0
+	}
+
+	pub fn checked_add(self, other: Amount) -> Option<Amount> {
+		self.0.checked_add(other.0).map(Amount)
+	}
+
+	pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+		self.0.checked_sub(other.0).map(Amount)
+	}
+}
+
+impl std::convert::From<u64> for Amount {
+	fn from(value: u64) -> Amount {
+		Amount(value)
+	}
+}
+
+impl std::convert::From<Amount> for u64 {
+	fn from(amount: Amount) -> u64 {
+		amount.0
+	}
+}
+
+impl std::fmt::Display for Amount {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl std::str::FromStr for Amount {
+	type Err = std::num::ParseIntError;
+
+	fn from_str(s: &str) -> Result<Amount, Self::Err> {
+		s.parse::<u64>().map(Amount)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Amount;
+
+	#[test]
+	fn checked_arithmetic() {
+		let a = Amount::from(10);
+		assert_eq!(a.checked_add(Amount::from(5)), Some(Amount::from(15)));
+		assert_eq!(a.checked_sub(Amount::from(11)), None);
+		assert_eq!(Amount::MAX.checked_add(Amount::from(1)), None);
+		assert_eq!("42".parse::<Amount>().unwrap(), Amount::from(42));
+	}
+}
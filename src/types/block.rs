@@ -1,12 +1,13 @@
 use serde::{Serialize, Deserialize};
 use crate::types::hash::{H256, Hashable};
-use crate::types::transaction::{Transaction, SignedTransaction};
+use crate::types::transaction::SignedTransaction;
 use rand::Rng;
-use ring::signature::KeyPair;
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::types::key_pair;
 use crate::types::merkle::MerkleTree;
-use crate::types::address::Address;
+use crate::types::amount::Amount;
+
+/// Amount credited to the ICO key in the genesis state of the development chain
+pub const ICO_AMOUNT: Amount = Amount::from_u64(1_000_000);
 
 //extern crate chrono;
 //use chrono:: prelude::*;
@@ -15,6 +16,7 @@ use crate::types::address::Address;
 pub struct Header{
     pub parent_hash: H256, 	// hash ptr to parent block
     pub nonce: u32,			// random integer for proof of work check
+    pub extra_nonce: u32,	// extends the nonce space, changed when all nonces were tried
     pub difficulty: H256,	// threshold for proof of work
     pub timestamp: u128, 	// timestamp when the block is generated
     pub merkle_root: H256, 	// merkle_root of signed trx in content
@@ -31,13 +33,58 @@ pub struct Block {
     pub content: Content,
 }
 
+/// Size of the fixed-layout header serialization
+pub const HEADER_SIZE: usize = 32 + 32 + 32 + 4 + 16 + 4;
+/// Length of the header prefix that does not change while trying nonces
+const HEADER_PREFIX_SIZE: usize = 32 + 32 + 32 + 4;
+
+impl Header {
+	/// Fixed-layout serialization, the one hashed for the proof of work: parent hash, merkle root,
+	/// difficulty, extra nonce, timestamp and nonce, integers in little endian.
+	/// The fields that stay constant while trying nonces come first, see `HeaderHasher`.
+	pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+		let mut bytes = [0u8; HEADER_SIZE];
+		bytes[0..32].copy_from_slice(self.parent_hash.as_ref());
+		bytes[32..64].copy_from_slice(self.merkle_root.as_ref());
+		bytes[64..96].copy_from_slice(self.difficulty.as_ref());
+		bytes[96..100].copy_from_slice(&self.extra_nonce.to_le_bytes());
+		bytes[100..116].copy_from_slice(&self.timestamp.to_le_bytes());
+		bytes[116..120].copy_from_slice(&self.nonce.to_le_bytes());
+		bytes
+	}
+}
+
 impl Hashable for Header{
     fn hash(&self) -> H256{
-        let encodehead: Vec <u8> = bincode:: serialize(&self).unwrap();
-        ring::digest::digest(&ring::digest::SHA256, &encodehead[..]).into()
+        ring::digest::digest(&ring::digest::SHA256, &self.to_bytes()).into()
     }
 }
 
+/// Hashes a header for different timestamps and nonces, reusing the SHA256 state (midstate)
+/// of the prefix that stays constant.
+#[derive(Clone)]
+pub struct HeaderHasher {
+	midstate: ring::digest::Context,
+}
+
+impl HeaderHasher {
+	pub fn new(header: &Header) -> Self {
+		let mut midstate = ring::digest::Context::new(&ring::digest::SHA256);
+		midstate.update(&header.to_bytes()[..HEADER_PREFIX_SIZE]);
+		HeaderHasher{midstate}
+	}
+
+	/// Hash of the header with the given timestamp and nonce
+	pub fn hash(&self, timestamp: u128, nonce: u32) -> H256 {
+		let mut suffix = [0u8; HEADER_SIZE - HEADER_PREFIX_SIZE];
+		suffix[..16].copy_from_slice(&timestamp.to_le_bytes());
+		suffix[16..].copy_from_slice(&nonce.to_le_bytes());
+		let mut context = self.midstate.clone();
+		context.update(&suffix);
+		context.finish().into()
+	}
+}
+
 
 impl Hashable for Block {
     fn hash(&self) -> H256 {
@@ -64,6 +111,16 @@ impl Block {
 
 		return hashes;
 	}
+
+	/// Compute the merkle root of the transactions in this block
+	pub fn compute_merkle_root(&self) -> H256 {
+		MerkleTree::new(&self.content.data).root()
+	}
+
+	/// Size of the serialized block in bytes
+	pub fn size(&self) -> usize {
+		bincode::serialized_size(&self).unwrap() as usize
+	}
 }
 
 //#[cfg(any(test, test_utilities))]
@@ -74,11 +131,11 @@ pub fn generate_random_block(parent: &H256) -> Block {
 	let mut ts: u128;
 	let mut head: Header;
 	let mut block: Block;
-	let root: H256 = hex!("4b3947f87e40c184f6394d4f0916a43b1395d51855e39b4ffe400b2be3797d98").into();
 	//let difficulty = hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
 	let difficulty = hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
 
 	let trx: Vec<SignedTransaction> = vec![];
+	let root: H256 = MerkleTree::new(&trx).root();
 	let content: Content = Content{data: trx};
 
 	loop {
@@ -87,6 +144,7 @@ pub fn generate_random_block(parent: &H256) -> Block {
 		head = Header{
 			parent_hash: *parent,
 			nonce: nonce,
+			extra_nonce: 0,
 			difficulty: difficulty,
 			timestamp: ts,
 			merkle_root: root
@@ -99,36 +157,19 @@ pub fn generate_random_block(parent: &H256) -> Block {
 	return block;
 }
 
-pub fn generate_genesis_block() -> Block {
-	let parent: H256 = hex!("0000000000000000000000000000000000000000000000000000000000000000").into();
-	let nonceval: u32 = 0;
-	let ts: u128 = 0;
-
-	let difficulty = hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
-
-	let mut vect: Vec<SignedTransaction> = vec![];
-	// Initial Coin Offering (ICO) transactions
-	let key = key_pair::from_seed(0);
-	let trx = Transaction {
-		receiver: Address::from_public_key_bytes(key.public_key().as_ref()),
-		value: 1e6 as u32,
-		account_nonce: 0
-	};
-	let signed_trx = SignedTransaction::new(trx, &key);
-	vect.push(signed_trx);
-
-	//let root: H256 = hex!("4b3947f87e40c184f6394d4f0916a43b1395d51855e39b4ffe400b2be3797d98").into();
-	let merkle_tree: MerkleTree = MerkleTree::new(&vect);
-	let root: H256 = merkle_tree.root();
-
-	let head: Header = Header{
-		parent_hash: parent,
-		nonce: nonceval,
-		difficulty: difficulty,
-		timestamp: ts,
-		merkle_root: root
-	};
-	let content: Content = Content{data: vect};
-	let block: Block = Block{header: head, content: content};
-	return block;
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn header_hasher_matches_header_hash() {
+		let mut header = generate_random_block(&H256::default()).header;
+		header.extra_nonce = 7;
+		let hasher = HeaderHasher::new(&header);
+		assert_eq!(hasher.hash(header.timestamp, header.nonce), header.hash());
+		header.timestamp += 1;
+		header.nonce = header.nonce.wrapping_add(1);
+		assert_eq!(hasher.hash(header.timestamp, header.nonce), header.hash());
+		assert_eq!(header.to_bytes().len(), HEADER_SIZE);
+	}
 }
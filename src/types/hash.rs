@@ -89,6 +89,17 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    /// Parse the 64 hex digits printed by `Display`
+    fn from_str(s: &str) -> Result<H256, Self::Err> {
+        let mut raw_hash: [u8; 32] = [0; 32];
+        hex::decode_to_slice(s, &mut raw_hash)?;
+        Ok(H256(raw_hash))
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
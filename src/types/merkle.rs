@@ -27,30 +27,23 @@ impl MerkleTree {
 
 		let leaf_size: usize = data.len();
 		let num_levels: u32 = (leaf_size as f64).log(2.0).ceil() as u32;
-		println!("leaf_size: {}, num_levels: {}", leaf_size, num_levels);
 		let mut hash: Vec<H256> = Vec::new();
 		let mut i: usize = 0;
 		for datum in data.iter() {
 			hash.push(datum.hash());
-			println!("hash[{}]: {}", i, hash[i]);
 			i = i+1;
 		}
 
 		let mut level_size: usize = leaf_size;
 		i = 0;
 		let mut total_nodes: usize = 0;
-		for level in 0..num_levels {
-			println!("level: {}, level_size: {}, total_nodes: {}", level, level_size, total_nodes);
+		for _ in 0..num_levels {
 			while i+1 < total_nodes + level_size {
-				println!("i:{}", i);
 				hash.push(combine_hash(hash[i], hash[i+1]));
-				println!("h1:{}\nh2:{}\ncombined:{}", hash[i], hash[i+1], hash[hash.len()-1] );
 				i += 2;
 			}
 			if level_size%2==1 {
-				println!("i:{}", i);
 				hash.push(combine_hash(hash[i], hash[i]));
-				println!("h1:{}\nh2:{}\ncombined:{}", hash[i], hash[i], hash[hash.len()-1] );
 				i += 1;
 			}
 			total_nodes += level_size;
@@ -71,6 +64,9 @@ impl MerkleTree {
 		//let hash: H256 = [0u8; 32].into();
 		//return hash;
 		// return self.hash[0];
+		if self.hash.is_empty() { // the root of an empty tree is all zeros
+			return H256::default();
+		}
 		let root_idx: usize = self.hash.len() - 1;
 		return self.hash[root_idx];
 	}
@@ -123,7 +119,6 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
 		} else {
 			hash = combine_hash(*sibling, hash);
 		}
-		println!("level: {}\nsibling: {}\nhash: {}", level, *sibling, hash);
 		level += 1;
 	}
 
@@ -1,6 +1,8 @@
 pub mod address;
+pub mod amount;
 pub mod block;
 pub mod hash;
 pub mod merkle;
 pub mod key_pair;
+pub mod script;
 pub mod transaction;
\ No newline at end of file
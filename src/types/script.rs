@@ -0,0 +1,404 @@
+use serde::{Serialize, Deserialize};
+use ring::digest::{Context, SHA256};
+use ring::signature;
+use crate::types::address::Address;
+
+/// Maximum number of operations in a single script
+pub const MAX_SCRIPT_OPS: usize = 201;
+/// Maximum number of items on the stack while executing a script
+pub const MAX_STACK_SIZE: usize = 1000;
+/// Maximum number of public keys in an `OP_CHECKMULTISIG`
+pub const MAX_MULTISIG_KEYS: usize = 20;
+/// Maximum size in bytes of an item pushed on the stack
+pub const MAX_PUSH_SIZE: usize = 520;
+
+/// Opcodes of the transaction scripting language
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+	Push(Vec<u8>),			// push raw bytes (public key, signature, preimage, hash)
+	Num(u128),				// push a number, used for thresholds and locks
+	Dup,
+	Drop,
+	Swap,
+	Equal,
+	EqualVerify,
+	Verify,
+	Sha256,
+	CheckSig,				// <sig> <pubkey> -> 1/0
+	CheckSigVerify,
+	CheckMultiSig,			// <sig_1>..<sig_m> <m> <pubkey_1>..<pubkey_n> <n> -> 1/0
+	CheckLockTimeVerify,	// fails unless the transaction lock_time is at least the top item
+	CheckLockHeightVerify,	// fails unless the transaction lock_height is at least the top item
+	If,
+	Else,
+	EndIf,
+	Return,					// always fails
+}
+
+impl Op {
+	fn is_push(&self) -> bool {
+		matches!(self, Op::Push(_) | Op::Num(_))
+	}
+}
+
+/// A script is a list of operations. Accounts are locked by a locking script, and a transaction
+/// spending from such an account provides unlocking data that is executed before it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Script(pub Vec<Op>);
+
+impl Script {
+	pub fn new(ops: Vec<Op>) -> Self {
+		Script(ops)
+	}
+
+	/// Address of the account locked by this script, i.e. the hash of the script
+	pub fn address(&self) -> Address {
+		let encoded: Vec<u8> = bincode::serialize(&self).unwrap();
+		let mut context = Context::new(&SHA256);
+		context.update(b"script");
+		context.update(&encoded);
+		let digest = context.finish();
+		let mut buffer: [u8; 20] = [0; 20];
+		buffer.copy_from_slice(&digest.as_ref()[12..32]);
+		buffer.into()
+	}
+
+	/// Whether the script only pushes data, as required for unlocking scripts
+	pub fn is_push_only(&self) -> bool {
+		self.0.iter().all(|op| op.is_push())
+	}
+
+	/// Pay to a single public key
+	pub fn pay_to_public_key(public_key: &[u8]) -> Self {
+		Script(vec![Op::Push(public_key.to_vec()), Op::CheckSig])
+	}
+
+	/// M-of-N multisig over the given public keys
+	pub fn multisig(threshold: u8, public_keys: &[Vec<u8>]) -> Self {
+		let mut ops: Vec<Op> = vec![Op::Num(threshold as u128)];
+		for key in public_keys.iter() {
+			ops.push(Op::Push(key.clone()));
+		}
+		ops.push(Op::Num(public_keys.len() as u128));
+		ops.push(Op::CheckMultiSig);
+		Script(ops)
+	}
+
+	/// Hash-time-locked contract: `receiver` can spend by revealing the preimage of `hash`,
+	/// and `sender` can take the funds back once the chain reaches `lock_height`.
+	/// Unlocking data is `<sig> <preimage> 1` for the receiver and `<sig> 0` for the refund.
+	pub fn hash_time_lock(hash: &[u8], receiver: &[u8], sender: &[u8], lock_height: u32) -> Self {
+		Script(vec![
+			Op::If,
+				Op::Sha256, Op::Push(hash.to_vec()), Op::EqualVerify,
+				Op::Push(receiver.to_vec()),
+			Op::Else,
+				Op::Num(lock_height as u128), Op::CheckLockHeightVerify, Op::Drop,
+				Op::Push(sender.to_vec()),
+			Op::EndIf,
+			Op::CheckSig,
+		])
+	}
+}
+
+/// Data of the spending transaction that scripts can check
+pub struct ScriptContext<'a> {
+	pub message: &'a [u8],			// bytes covered by the signatures
+	pub lock_height: Option<u32>,	// lock height of the spending transaction
+	pub lock_time: Option<u128>,	// lock time of the spending transaction
+}
+
+fn encode_num(n: u128) -> Vec<u8> {
+	let mut bytes = n.to_le_bytes().to_vec();
+	while bytes.last() == Some(&0) {
+		bytes.pop();
+	}
+	bytes
+}
+
+fn decode_num(bytes: &[u8]) -> Result<u128, &'static str> {
+	if bytes.len() > 16 {
+		return Err("script number overflow");
+	}
+	let mut buffer: [u8; 16] = [0; 16];
+	buffer[..bytes.len()].copy_from_slice(bytes);
+	Ok(u128::from_le_bytes(buffer))
+}
+
+fn is_true(bytes: &[u8]) -> bool {
+	bytes.iter().any(|b| *b != 0)
+}
+
+fn check_sig(sign: &[u8], public_key: &[u8], message: &[u8]) -> bool {
+	let unparsed_public_key = signature::UnparsedPublicKey::new(&signature::ED25519, public_key);
+	unparsed_public_key.verify(message, sign).is_ok()
+}
+
+struct Machine<'a> {
+	stack: Vec<Vec<u8>>,
+	ctx: &'a ScriptContext<'a>,
+}
+
+impl<'a> Machine<'a> {
+	fn push(&mut self, item: Vec<u8>) -> Result<(), &'static str> {
+		if item.len() > MAX_PUSH_SIZE {
+			return Err("pushed item too large");
+		}
+		if self.stack.len() >= MAX_STACK_SIZE {
+			return Err("script stack overflow");
+		}
+		self.stack.push(item);
+		Ok(())
+	}
+
+	fn pop(&mut self) -> Result<Vec<u8>, &'static str> {
+		self.stack.pop().ok_or("script stack underflow")
+	}
+
+	fn pop_num(&mut self) -> Result<u128, &'static str> {
+		let item = self.pop()?;
+		decode_num(&item)
+	}
+
+	fn push_bool(&mut self, b: bool) -> Result<(), &'static str> {
+		self.push(if b { vec![1] } else { vec![] })
+	}
+
+	fn check_multisig(&mut self) -> Result<bool, &'static str> {
+		// checked before the cast, which would truncate larger numbers
+		let num_keys = self.pop_num()?;
+		if num_keys > MAX_MULTISIG_KEYS as u128 {
+			return Err("too many public keys in multisig");
+		}
+		let num_keys = num_keys as usize;
+		let mut keys: Vec<Vec<u8>> = vec![];
+		for _ in 0..num_keys {
+			keys.push(self.pop()?);
+		}
+		keys.reverse();
+		let num_sigs = self.pop_num()?;
+		if num_sigs > num_keys as u128 {
+			return Err("multisig threshold larger than number of keys");
+		}
+		let num_sigs = num_sigs as usize;
+		let mut sigs: Vec<Vec<u8>> = vec![];
+		for _ in 0..num_sigs {
+			sigs.push(self.pop()?);
+		}
+		sigs.reverse();
+
+		// signatures must appear in the same order as their keys
+		let mut key_idx = 0;
+		for sign in sigs.iter() {
+			while key_idx < keys.len() && !check_sig(sign, &keys[key_idx], self.ctx.message) {
+				key_idx += 1;
+			}
+			if key_idx == keys.len() {
+				return Ok(false);
+			}
+			key_idx += 1;
+		}
+		Ok(true)
+	}
+
+	fn run(&mut self, script: &Script) -> Result<(), &'static str> {
+		if script.0.len() > MAX_SCRIPT_OPS {
+			return Err("script too long");
+		}
+		// one entry per enclosing If, whether its active branch is being executed
+		let mut exec_stack: Vec<bool> = vec![];
+		for op in script.0.iter() {
+			let executing = exec_stack.iter().all(|b| *b);
+			match op {
+				Op::If => {
+					let branch = if executing { is_true(&self.pop()?) } else { false };
+					exec_stack.push(branch);
+					continue;
+				}
+				Op::Else => {
+					let branch = exec_stack.pop().ok_or("Else without If")?;
+					exec_stack.push(!branch);
+					continue;
+				}
+				Op::EndIf => {
+					exec_stack.pop().ok_or("EndIf without If")?;
+					continue;
+				}
+				_ if !executing => continue,
+				_ => {}
+			}
+
+			match op {
+				Op::Push(bytes) => self.push(bytes.clone())?,
+				Op::Num(n) => self.push(encode_num(*n))?,
+				Op::Dup => {
+					let item = self.stack.last().ok_or("script stack underflow")?.clone();
+					self.push(item)?;
+				}
+				Op::Drop => {
+					self.pop()?;
+				}
+				Op::Swap => {
+					let a = self.pop()?;
+					let b = self.pop()?;
+					self.push(a)?;
+					self.push(b)?;
+				}
+				Op::Equal | Op::EqualVerify => {
+					let a = self.pop()?;
+					let b = self.pop()?;
+					if let Op::EqualVerify = op {
+						if a != b {
+							return Err("EqualVerify failed");
+						}
+					} else {
+						self.push_bool(a == b)?;
+					}
+				}
+				Op::Verify => {
+					if !is_true(&self.pop()?) {
+						return Err("Verify failed");
+					}
+				}
+				Op::Sha256 => {
+					let item = self.pop()?;
+					let digest = ring::digest::digest(&SHA256, &item);
+					self.push(digest.as_ref().to_vec())?;
+				}
+				Op::CheckSig | Op::CheckSigVerify => {
+					let public_key = self.pop()?;
+					let sign = self.pop()?;
+					let valid = check_sig(&sign, &public_key, self.ctx.message);
+					if let Op::CheckSigVerify = op {
+						if !valid {
+							return Err("CheckSigVerify failed");
+						}
+					} else {
+						self.push_bool(valid)?;
+					}
+				}
+				Op::CheckMultiSig => {
+					let valid = self.check_multisig()?;
+					self.push_bool(valid)?;
+				}
+				Op::CheckLockTimeVerify => {
+					// the lock stays on the stack, as in Bitcoin
+					let lock = decode_num(self.stack.last().ok_or("script stack underflow")?)?;
+					if self.ctx.lock_time.unwrap_or(0) < lock {
+						return Err("lock time not reached");
+					}
+				}
+				Op::CheckLockHeightVerify => {
+					let lock = decode_num(self.stack.last().ok_or("script stack underflow")?)?;
+					if (self.ctx.lock_height.unwrap_or(0) as u128) < lock {
+						return Err("lock height not reached");
+					}
+				}
+				Op::Return => return Err("Return executed"),
+				Op::If | Op::Else | Op::EndIf => unreachable!(),
+			}
+		}
+		if !exec_stack.is_empty() {
+			return Err("unbalanced If");
+		}
+		Ok(())
+	}
+}
+
+/// Run the unlocking data followed by the locking script. The spend is valid if both run without
+/// error and leave a true value on top of the stack.
+pub fn execute(unlocking: &Script, locking: &Script, ctx: &ScriptContext) -> Result<(), &'static str> {
+	if !unlocking.is_push_only() {
+		return Err("unlocking script must only push data");
+	}
+	let mut machine = Machine{stack: vec![], ctx};
+	machine.run(unlocking)?;
+	machine.run(locking)?;
+	match machine.stack.last() {
+		Some(top) if is_true(top) => Ok(()),
+		_ => Err("script evaluated to false"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::key_pair;
+	use ring::signature::KeyPair;
+
+	fn context(message: &[u8], lock_height: Option<u32>) -> ScriptContext<'_> {
+		ScriptContext{message, lock_height, lock_time: None}
+	}
+
+	#[test]
+	fn pay_to_public_key() {
+		let key = key_pair::random();
+		let locking = Script::pay_to_public_key(key.public_key().as_ref());
+		let sign = key.sign(b"message").as_ref().to_vec();
+		let unlocking = Script::new(vec![Op::Push(sign)]);
+		assert!(execute(&unlocking, &locking, &context(b"message", None)).is_ok());
+		assert!(execute(&unlocking, &locking, &context(b"other message", None)).is_err());
+	}
+
+	#[test]
+	fn multisig_two_of_three() {
+		let keys: Vec<_> = (0..3).map(|_| key_pair::random()).collect();
+		let public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key().as_ref().to_vec()).collect();
+		let locking = Script::multisig(2, &public_keys);
+		let sign = |i: usize| Op::Push(keys[i].sign(b"message").as_ref().to_vec());
+
+		let unlocking = Script::new(vec![sign(0), sign(2)]);
+		assert!(execute(&unlocking, &locking, &context(b"message", None)).is_ok());
+		// out of key order
+		let unlocking = Script::new(vec![sign(2), sign(0)]);
+		assert!(execute(&unlocking, &locking, &context(b"message", None)).is_err());
+		let unlocking = Script::new(vec![sign(1)]);
+		assert!(execute(&unlocking, &locking, &context(b"message", None)).is_err());
+	}
+
+	#[test]
+	fn limits_are_enforced() {
+		let key = key_pair::random();
+		let public_key = key.public_key().as_ref().to_vec();
+		let sign = Op::Push(key.sign(b"message").as_ref().to_vec());
+		// 2^64 + 1 keys would be read as 1 key if truncated
+		let locking = Script::new(vec![Op::Push(public_key.clone()), Op::Num((1u128 << 64) + 1), Op::CheckMultiSig]);
+		assert!(execute(&Script::new(vec![sign.clone(), Op::Num(1)]), &locking, &context(b"message", None)).is_err());
+		let locking = Script::new(vec![Op::Push(public_key), Op::Num(1), Op::CheckMultiSig]);
+		assert!(execute(&Script::new(vec![sign.clone(), Op::Num((1u128 << 64) + 1)]), &locking, &context(b"message", None)).is_err());
+		assert!(execute(&Script::new(vec![sign, Op::Num(1)]), &locking, &context(b"message", None)).is_ok());
+
+		let locking = Script::new(vec![Op::Drop, Op::Num(1)]);
+		assert!(execute(&Script::new(vec![Op::Push(vec![0; MAX_PUSH_SIZE])]), &locking, &context(b"message", None)).is_ok());
+		assert!(execute(&Script::new(vec![Op::Push(vec![0; MAX_PUSH_SIZE + 1])]), &locking, &context(b"message", None)).is_err());
+	}
+
+	#[test]
+	fn hash_time_lock() {
+		let receiver = key_pair::random();
+		let sender = key_pair::random();
+		let preimage = b"secret".to_vec();
+		let hash = ring::digest::digest(&SHA256, &preimage).as_ref().to_vec();
+		let locking = Script::hash_time_lock(&hash, receiver.public_key().as_ref(), sender.public_key().as_ref(), 10);
+
+		let claim = Script::new(vec![
+			Op::Push(receiver.sign(b"message").as_ref().to_vec()), Op::Push(preimage), Op::Num(1),
+		]);
+		assert!(execute(&claim, &locking, &context(b"message", None)).is_ok());
+		let wrong_preimage = Script::new(vec![
+			Op::Push(receiver.sign(b"message").as_ref().to_vec()), Op::Push(b"guess".to_vec()), Op::Num(1),
+		]);
+		assert!(execute(&wrong_preimage, &locking, &context(b"message", None)).is_err());
+
+		let refund = Script::new(vec![Op::Push(sender.sign(b"message").as_ref().to_vec()), Op::Num(0)]);
+		assert!(execute(&refund, &locking, &context(b"message", Some(9))).is_err());
+		assert!(execute(&refund, &locking, &context(b"message", Some(10))).is_ok());
+	}
+
+	#[test]
+	fn unlocking_must_be_push_only() {
+		let locking = Script::new(vec![Op::Num(1)]);
+		let unlocking = Script::new(vec![Op::Return]);
+		assert!(execute(&unlocking, &locking, &context(b"", None)).is_err());
+	}
+}
@@ -2,17 +2,26 @@
 use serde::{Serialize,Deserialize};
 use ring::signature::{self, Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use rand::{Rng, distributions::Alphanumeric};
+use crate::blockchain::State;
 use crate::types::address::Address;
+use crate::types::amount::Amount;
 use crate::types::hash::{H256, Hashable};
 use crate::types::key_pair;
+use crate::types::script::{self, Script, ScriptContext};
+use crate::error::{StorageError, TransactionError};
 use std::collections::VecDeque;
 use std::collections::HashMap;
 
+/// Transactions to mine are refused when their nonce is further than this ahead of the next nonce of the
+/// sender, they could not be mined soon and would only take room in the mempool
+pub const MAX_NONCE_GAP: u32 = 100;
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 
 pub struct TransactionMempool{
 	tx_hash_queue: VecDeque<H256>,
 	tx_map: HashMap<H256, SignedTransaction>,
+	max_queued: Option<usize>,	// queued transactions from which new ones are refused, unbounded if None
 }
   
 impl TransactionMempool{
@@ -20,43 +29,85 @@ impl TransactionMempool{
 	pub fn new() -> Self{
 		TransactionMempool{
 			tx_hash_queue: VecDeque::new(), 
-			tx_map: HashMap::new()
+			tx_map: HashMap::new(),
+			max_queued: None,
 		}
 	}
 
-	/// Insert a transaction in mempool
-	pub fn insert(&mut self, trx: &SignedTransaction, push_in_queue: bool){
-		if trx.verify(){
-			let hash = trx.hash();
-			if push_in_queue { // in case the transaction needs to be mined in the block
-				self.tx_hash_queue.push_back(hash);
+	/// Initialise a new mempool refusing transactions to mine once `max_queued` of them wait in it
+	pub fn with_max_queued(max_queued: usize) -> Self {
+		TransactionMempool{max_queued: Some(max_queued), ..Self::new()}
+	}
+
+	/// Insert a transaction in mempool. Transactions to mine are queued with the state of the tip `tip_state`:
+	/// their sender must exist and afford the value and the fee, and their nonce must be one of the next
+	/// `MAX_NONCE_GAP` ones of the sender. Without a state, the transaction is only known, e.g. from a block.
+	pub fn insert(&mut self, trx: &SignedTransaction, tip_state: Option<&State>) -> Result<(), TransactionError> {
+		if !trx.verify(){
+			return Err(TransactionError::InvalidSignature);
+		}
+		let hash = trx.hash();
+		if let Some(state) = tip_state { // in case the transaction needs to be mined in the block
+			let (nonce, balance) = state.get_balance(trx.sender_address()).map_err(|_| TransactionError::UnknownSender)?;
+			let expected = nonce.checked_add(1).ok_or(TransactionError::NonceOverflow)?;
+			let found = trx.account_nonce();
+			if found < expected || found - expected >= MAX_NONCE_GAP {
+				return Err(TransactionError::InvalidNonce{expected, found});
 			}
-			self.tx_map.insert(hash, trx.clone());
-		} else {
-			println!("recieved invalid transaction: {:#?}", trx);
+			if !matches!(trx.value().checked_add(trx.fee()), Some(total) if total <= balance) {
+				return Err(TransactionError::InsufficientBalance);
+			}
+			if matches!(self.max_queued, Some(max) if self.tx_hash_queue.len() >= max) {
+				return Err(TransactionError::MempoolFull);
+			}
+			self.tx_hash_queue.push_back(hash);
 		}
+		self.tx_map.insert(hash, trx.clone());
+		Ok(())
+	}
+
+	/// Transactions that are final for a block at `height` on a parent with median time past `median_time_past`,
+	/// in queue order, without taking them out of the queue. Those not final yet wait in the queue for their lock.
+	pub fn queued(&self, height: u32, median_time_past: u128) -> Vec<SignedTransaction> {
+		self.tx_hash_queue.iter()
+			.filter_map(|hash| self.tx_map.get(hash))
+			.filter(|trx| trx.is_final(height, median_time_past))
+			.cloned()
+			.collect()
+	}
+
+	/// Take transactions out of the queue, e.g. once a block includes them. They stay known to the mempool.
+	pub fn remove_from_queue(&mut self, hashes: &[H256]) {
+		self.tx_hash_queue.retain(|hash| !hashes.contains(hash));
 	}
 
-	/// Dequeue a transaction from mempool queue
-	pub fn dequeue(&mut self) -> Result<SignedTransaction, &'static str> {
-		if self.tx_hash_queue.is_empty() {
-			return Err("empty queue in mempool");
-		} else {
-			let hash = self.tx_hash_queue.pop_front().unwrap();
-			let trx = &self.tx_map[ &hash ];
-			return Ok(trx.clone());
+	/// Put transactions back at the front of the queue, keeping their order, e.g. when a reorg disconnects
+	/// the block including them. Those already waiting to be mined stay where they are.
+	pub fn requeue(&mut self, trxs: &[SignedTransaction]) {
+		for trx in trxs.iter().rev() {
+			let hash = trx.hash();
+			if self.tx_hash_queue.contains(&hash) {
+				continue;
+			}
+			self.tx_map.entry(hash).or_insert_with(|| trx.clone());
+			self.tx_hash_queue.push_front(hash);
 		}
 	}
 
+	/// Hashes of the transactions waiting to be mined, in queue order
+	pub fn pending_hashes(&self) -> Vec<H256> {
+		self.tx_hash_queue.iter().copied().collect()
+	}
+
     /// Check if a transaction hash is present
     pub fn is_hash_present(&self, hash: H256) -> bool {
         return self.tx_map.contains_key(&hash)
     }
 
     /// Retrieve a transaction corresponding to a hash
-    pub fn get_transaction(&self, hash: H256) -> Result<SignedTransaction, &'static str> {
+    pub fn get_transaction(&self, hash: H256) -> Result<SignedTransaction, StorageError> {
         if !self.tx_map.contains_key(&hash) {
-            return Err("invalid transaction hash");
+            return Err(StorageError::TransactionNotFound(hash));
         } else {
             let trx = &self.tx_map[ &hash ];
             return Ok(trx.clone());
@@ -67,15 +118,28 @@ impl TransactionMempool{
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
 	pub receiver: Address,
-	pub value: u32,
+	pub value: Amount,
+	pub fee: Amount,				// paid by the sender on top of the value, burned as blocks carry no reward
 	pub account_nonce: u32,
+	pub lock_height: Option<u32>,	// earliest block height that may include the transaction
+	pub lock_time: Option<u128>,	// earliest median time past (ms) of the parent of a block that may include the transaction
+}
+
+impl Transaction {
+	/// Whether the transaction may be included in a block at `height` whose parent has the median time past
+	/// `median_time_past`. The block timestamp is not used, miners are free to move it forward.
+	pub fn is_final(&self, height: u32, median_time_past: u128) -> bool {
+		self.lock_height.unwrap_or(0) <= height && self.lock_time.unwrap_or(0) <= median_time_past
+	}
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
 	transaction: Transaction,
-	sign: Vec<u8>,
-	public_key: Vec<u8>,
+	threshold: u8,					// number of signatures required to spend from the sender account
+	public_keys: Vec<Vec<u8>>,		// sorted public keys controlling the sender account
+	signs: Vec<(u8, Vec<u8>)>,		// (index into public_keys, signature)
+	script: Option<(Script, Script)>,	// (locking script of the sender account, unlocking data) when spending from a script account
 }
 
 impl SignedTransaction {
@@ -83,31 +147,163 @@ impl SignedTransaction {
 		let signature: Signature = sign(&trx, &key);
 		let signed_trx = SignedTransaction{
 			transaction: trx,
-			sign: signature.as_ref().to_vec(),
-			public_key: key.public_key().as_ref().to_vec()
+			threshold: 1,
+			public_keys: vec![key.public_key().as_ref().to_vec()],
+			signs: vec![(0, signature.as_ref().to_vec())],
+			script: None,
 		};
 		return signed_trx;
 	}
 
+	/// Create a transaction spending from an M-of-N multisig account, without any signatures yet.
+	/// Signatures are then added one by one with `add_signature`.
+	pub fn new_multisig(trx: Transaction, threshold: u8, public_keys: &[Vec<u8>]) -> Self {
+		let mut keys: Vec<Vec<u8>> = public_keys.to_vec();
+		keys.sort();
+		keys.dedup();
+		SignedTransaction{
+			transaction: trx,
+			threshold,
+			public_keys: keys,
+			signs: vec![],
+			script: None,
+		}
+	}
+
+	/// Create a transaction spending from the account locked by `locking_script`, with empty unlocking data.
+	/// Signatures for the unlocking data are made with `sign_for_script`.
+	pub fn new_script(trx: Transaction, locking_script: Script) -> Self {
+		SignedTransaction{
+			transaction: trx,
+			threshold: 0,
+			public_keys: vec![],
+			signs: vec![],
+			script: Some((locking_script, Script::default())),
+		}
+	}
+
+	/// Sign the transaction with `key`, returning a signature to be pushed in the unlocking data
+	pub fn sign_for_script(&self, key: &Ed25519KeyPair) -> Vec<u8> {
+		key.sign(&self.signing_message()).as_ref().to_vec()
+	}
+
+	/// Set the unlocking data of a transaction spending from a script account
+	pub fn set_unlocking_script(&mut self, unlocking_script: Script) -> Result<(), &'static str> {
+		match &mut self.script {
+			Some((_locking, unlocking)) => {
+				*unlocking = unlocking_script;
+				Ok(())
+			}
+			None => Err("sender account is not locked by a script"),
+		}
+	}
+
+	/// Add the signature of one of the keys controlling the sender account
+	pub fn add_signature(&mut self, key: &Ed25519KeyPair) -> Result<(), &'static str> {
+		let public_key = key.public_key().as_ref();
+		let index = match self.public_keys.iter().position(|k| k.as_slice() == public_key) {
+			Some(i) => i as u8,
+			None => return Err("key does not control the sender account"),
+		};
+		if self.signs.iter().any(|(i, _)| *i == index) {
+			return Err("transaction already signed by this key");
+		}
+		let message = self.signing_message();
+		self.signs.push((index, key.sign(&message).as_ref().to_vec()));
+		self.signs.sort_by_key(|(i, _)| *i);
+		Ok(())
+	}
+
+	/// Check the signatures against the public keys, and that at least `threshold` distinct keys signed.
+	/// For script accounts, run the unlocking data against the locking script instead.
 	pub fn verify(&self) -> bool {
-		return verify(&self.transaction, &self.public_key, &self.sign);
+		if let Some((locking, unlocking)) = &self.script {
+			if !self.public_keys.is_empty() || !self.signs.is_empty() {
+				return false;
+			}
+			let message = self.signing_message();
+			let ctx = ScriptContext{
+				message: &message,
+				lock_height: self.transaction.lock_height,
+				lock_time: self.transaction.lock_time,
+			};
+			return script::execute(unlocking, locking, &ctx).is_ok();
+		}
+
+		let num_keys = self.public_keys.len();
+		if self.threshold == 0 || self.threshold as usize > num_keys || num_keys > u8::MAX as usize {
+			return false;
+		}
+		// keys must be strictly sorted, so that every account has a single key set and address
+		if self.public_keys.windows(2).any(|w| w[0] >= w[1]) {
+			return false;
+		}
+		// one signature per key, in key order
+		if self.signs.windows(2).any(|w| w[0].0 >= w[1].0) {
+			return false;
+		}
+		if self.signs.len() < self.threshold as usize {
+			return false;
+		}
+
+		let message = self.signing_message();
+		for (index, signature) in self.signs.iter() {
+			let public_key = match self.public_keys.get(*index as usize) {
+				Some(k) => k,
+				None => return false,
+			};
+			let unparsed_public_key = signature::UnparsedPublicKey::new(&signature::ED25519, &public_key[..]);
+			if unparsed_public_key.verify(&message, &signature[..]).is_err() {
+				return false;
+			}
+		}
+		true
+	}
+
+	/// Whether the sender account is controlled by a single key
+	pub fn is_single_key(&self) -> bool {
+		self.script.is_none() && self.threshold == 1 && self.public_keys.len() == 1
 	}
 
 	pub fn sender_address(&self) -> Address {
-		return Address::from_public_key_bytes(self.public_key.as_ref());
+		if let Some((locking, _unlocking)) = &self.script {
+			return locking.address();
+		}
+		if self.is_single_key() {
+			return Address::from_public_key_bytes(self.public_keys[0].as_ref());
+		}
+		Address::from_multisig(self.threshold, &self.public_keys)
 	}
 
 	pub fn receiver_address(&self) -> Address {
 		return self.transaction.receiver;
 	}
 
-	pub fn value(&self) -> u32 {
+	pub fn value(&self) -> Amount {
 		return self.transaction.value;
 	}
 
+	pub fn fee(&self) -> Amount {
+		self.transaction.fee
+	}
+
 	pub fn account_nonce(&self) -> u32 {
 		return self.transaction.account_nonce;
 	}
+
+	pub fn is_final(&self, height: u32, median_time_past: u128) -> bool {
+		self.transaction.is_final(height, median_time_past)
+	}
+
+	/// Bytes covered by the signatures. Multisig and script signatures also commit to the sender address, so that a
+	/// cosigner's signature cannot be replayed on another account sharing the same key.
+	fn signing_message(&self) -> Vec<u8> {
+		let mut message: Vec<u8> = serde_json::to_string(&self.transaction).unwrap().into_bytes();
+		if !self.is_single_key() {
+			message.extend_from_slice(self.sender_address().as_ref());
+		}
+		message
+	}
 }
 
 impl Hashable for SignedTransaction{
@@ -164,7 +360,7 @@ pub fn generate_random_transaction() -> Transaction {
 	let rb = r.as_bytes();
 
 	let mut rng = rand::thread_rng();
-	let val: u32 = rng.gen();
+	let val: Amount = Amount::from(rng.gen::<u32>() as u64);
 	let ac_nonce: u32 = rng.gen(); // maintain last account_nonce for each pk, set ac_nonce = last_ac_nonce + 1
 
 	//let s = hex!("00");
@@ -172,7 +368,10 @@ pub fn generate_random_transaction() -> Transaction {
 	let t = Transaction{
 		receiver: Address::from_public_key_bytes(&rb),
 		value: val,
-		account_nonce: ac_nonce
+		fee: Amount::ZERO,
+		account_nonce: ac_nonce,
+		lock_height: None,
+		lock_time: None,
 	};
 
 	return t;
@@ -197,8 +396,19 @@ pub fn generate_random_transaction() -> Transaction {
 mod tests {
     use super::*;
     use crate::types::key_pair;
+    use crate::types::script::Op;
     use ring::signature::KeyPair;
 
+    /// A state where `key` holds everything, with its first nonce still unused
+    fn funded_state(key: &Ed25519KeyPair) -> State {
+        State::new(vec![(Address::from_public_key_bytes(key.public_key().as_ref()), (0, Amount::MAX))])
+    }
+
+    fn transaction_with_nonce(key: &Ed25519KeyPair, nonce: u32) -> SignedTransaction {
+        let mut trx = generate_random_transaction();
+        trx.account_nonce = nonce;
+        SignedTransaction::new(trx, key)
+    }
 
     #[test]
     fn sign_verify() {
@@ -224,6 +434,116 @@ mod tests {
 		let signed_trx = SignedTransaction::new(trx, &key);
 		assert!(signed_trx.verify());
 	}
+	#[test]
+	fn multisig_threshold() {
+		let keys: Vec<Ed25519KeyPair> = (10..13).map(key_pair::from_seed).collect();
+		let public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key().as_ref().to_vec()).collect();
+		let mut signed_trx = SignedTransaction::new_multisig(generate_random_transaction(), 2, &public_keys);
+		assert!(!signed_trx.verify());
+		signed_trx.add_signature(&keys[2]).unwrap();
+		assert!(signed_trx.add_signature(&keys[2]).is_err());
+		assert!(!signed_trx.verify());
+		signed_trx.add_signature(&keys[0]).unwrap();
+		assert!(signed_trx.verify());
+		assert!(signed_trx.add_signature(&key_pair::from_seed(13)).is_err());
+	}
+	#[test]
+	fn multisig_address_ignores_key_order() {
+		let keys: Vec<Vec<u8>> = (10..13).map(|s| key_pair::from_seed(s).public_key().as_ref().to_vec()).collect();
+		let mut reversed = keys.clone();
+		reversed.reverse();
+		assert_eq!(Address::from_multisig(2, &keys), Address::from_multisig(2, &reversed));
+		assert_ne!(Address::from_multisig(2, &keys), Address::from_multisig(3, &keys));
+		let signed_trx = SignedTransaction::new_multisig(generate_random_transaction(), 2, &reversed);
+		assert_eq!(signed_trx.sender_address(), Address::from_multisig(2, &keys));
+	}
+	#[test]
+	fn script_account_spend() {
+		let keys: Vec<Ed25519KeyPair> = (10..12).map(key_pair::from_seed).collect();
+		let public_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.public_key().as_ref().to_vec()).collect();
+		let locking = Script::multisig(2, &public_keys);
+		let mut signed_trx = SignedTransaction::new_script(generate_random_transaction(), locking.clone());
+		assert_eq!(signed_trx.sender_address(), locking.address());
+		assert!(!signed_trx.verify());
+
+		let unlocking = Script::new(keys.iter().map(|k| Op::Push(signed_trx.sign_for_script(k))).collect());
+		signed_trx.set_unlocking_script(unlocking).unwrap();
+		assert!(signed_trx.verify());
+	}
+	#[test]
+	fn mempool_holds_locked_transactions() {
+		let key = key_pair::random();
+		let mut locked = generate_random_transaction();
+		locked.lock_height = Some(5);
+		locked.lock_time = Some(1000);
+		locked.account_nonce = 1;
+		let locked = SignedTransaction::new(locked, &key);
+		let unlocked = transaction_with_nonce(&key, 2);
+
+		let state = funded_state(&key);
+		let mut mempool = TransactionMempool::new();
+		mempool.insert(&locked, Some(&state)).unwrap();
+		mempool.insert(&unlocked, Some(&state)).unwrap();
+		let queued = |mempool: &TransactionMempool, height, median_time_past| -> Vec<H256> {
+			mempool.queued(height, median_time_past).iter().map(|trx| trx.hash()).collect()
+		};
+		assert_eq!(queued(&mempool, 1, 2000), vec![unlocked.hash()]);
+		assert_eq!(queued(&mempool, 5, 999), vec![unlocked.hash()]);
+		// the locked transaction kept its place in the queue
+		assert_eq!(queued(&mempool, 5, 1000), vec![locked.hash(), unlocked.hash()]);
+		assert_eq!(mempool.pending_hashes().len(), 2);
+	}
+	#[test]
+	fn mempool_queued_and_removed() {
+		let key = key_pair::random();
+		let first = transaction_with_nonce(&key, 1);
+		let second = transaction_with_nonce(&key, 2);
+
+		let state = funded_state(&key);
+		let mut mempool = TransactionMempool::new();
+		mempool.insert(&first, Some(&state)).unwrap();
+		mempool.insert(&second, Some(&state)).unwrap();
+		let queued: Vec<H256> = mempool.queued(1, 0).iter().map(|trx| trx.hash()).collect();
+		assert_eq!(queued, vec![first.hash(), second.hash()]);
+
+		mempool.remove_from_queue(&[first.hash()]);
+		assert!(mempool.is_hash_present(first.hash()));
+		assert_eq!(mempool.pending_hashes(), vec![second.hash()]);
+
+		let mut mempool = TransactionMempool::with_max_queued(1);
+		mempool.insert(&first, Some(&state)).unwrap();
+		assert_eq!(mempool.insert(&second, Some(&state)), Err(TransactionError::MempoolFull));
+		mempool.insert(&second, None).unwrap(); // only known, not queued
+		mempool.remove_from_queue(&[first.hash()]);
+		mempool.insert(&second, Some(&state)).unwrap();
+	}
+	#[test]
+	fn mempool_admits_transactions_that_can_apply() {
+		let funded = key_pair::random();
+		let state = funded_state(&funded);
+		let mut mempool = TransactionMempool::with_max_queued(2);
+
+		// validly signed transactions of an unknown sender can not fill the mempool
+		let unfunded = key_pair::random();
+		for nonce in 1..=3 {
+			assert_eq!(mempool.insert(&transaction_with_nonce(&unfunded, nonce), Some(&state)), Err(TransactionError::UnknownSender));
+		}
+		assert!(mempool.pending_hashes().is_empty());
+
+		// nor can used nonces, nonces far ahead or amounts above the balance
+		assert_eq!(mempool.insert(&transaction_with_nonce(&funded, 0), Some(&state)), Err(TransactionError::InvalidNonce{expected: 1, found: 0}));
+		let found = 1 + MAX_NONCE_GAP;
+		assert_eq!(mempool.insert(&transaction_with_nonce(&funded, found), Some(&state)), Err(TransactionError::InvalidNonce{expected: 1, found}));
+		let poor = State::new(vec![(Address::from_public_key_bytes(funded.public_key().as_ref()), (0, Amount::ZERO))]);
+		let mut trx = generate_random_transaction();
+		trx.account_nonce = 1;
+		trx.value = Amount::from(1);
+		assert_eq!(mempool.insert(&SignedTransaction::new(trx, &funded), Some(&poor)), Err(TransactionError::InsufficientBalance));
+
+		mempool.insert(&transaction_with_nonce(&funded, 1), Some(&state)).unwrap();
+		mempool.insert(&transaction_with_nonce(&funded, MAX_NONCE_GAP), Some(&state)).unwrap();
+		assert_eq!(mempool.pending_hashes().len(), 2);
+	}
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST